@@ -19,6 +19,8 @@ lib.render.restype = None
 def render(scene):
   scene_json = json.dumps(scene)
   scene_ptr = lib.decode_json_scene(scene_json.encode())
+  if not scene_ptr:
+    raise RuntimeError('Unable to prepare scene')
   lib.render(scene_ptr)
 
 def translate(vector, child):
@@ -84,3 +86,187 @@ def sphere(radius, center):
       }
     }
   }
+
+def plane(point, normal):
+  return {
+    'Primitive': {
+      'Plane': {
+        'point': point,
+        'normal': normal
+      }
+    }
+  }
+
+def disk(center, normal, radius):
+  return {
+    'Primitive': {
+      'Disk': {
+        'center': center,
+        'normal': normal,
+        'radius': radius
+      }
+    }
+  }
+
+def box(min, max):
+  return {
+    'Primitive': {
+      'Box': {
+        'min': min,
+        'max': max
+      }
+    }
+  }
+
+def cylinder(base, radius, height):
+  return {
+    'Primitive': {
+      'Cylinder': {
+        'base': base,
+        'radius': radius,
+        'height': height
+      }
+    }
+  }
+
+def cone(base, radius, height):
+  return {
+    'Primitive': {
+      'Cone': {
+        'base': base,
+        'radius': radius,
+        'height': height
+      }
+    }
+  }
+
+def torus(center, major_radius, minor_radius):
+  return {
+    'Primitive': {
+      'Torus': {
+        'center': center,
+        'major_radius': major_radius,
+        'minor_radius': minor_radius
+      }
+    }
+  }
+
+def csg(op, left, right):
+  return {
+    'Csg': {
+      'op': op,
+      'left': left,
+      'right': right
+    }
+  }
+
+def union(left, right):
+  return csg('Union', left, right)
+
+def intersection(left, right):
+  return csg('Intersection', left, right)
+
+def difference(left, right):
+  return csg('Difference', left, right)
+
+def falloff(constant=1.0, linear=0.0, quadratic=0.0):
+  return {
+    'constant': constant,
+    'linear': linear,
+    'quadratic': quadratic
+  }
+
+def point_light(color, intensity, position, falloff=falloff()):
+  return {
+    'Point': {
+      'color': color,
+      'intensity': intensity,
+      'position': position,
+      'falloff': falloff
+    }
+  }
+
+def directional_light(color, intensity, direction):
+  return {
+    'Directional': {
+      'color': color,
+      'intensity': intensity,
+      'direction': direction
+    }
+  }
+
+def spot_light(color, intensity, position, direction, angle, penumbra=0.0,
+               falloff=falloff()):
+  return {
+    'Spot': {
+      'color': color,
+      'intensity': intensity,
+      'position': position,
+      'direction': direction,
+      'angle': angle,
+      'penumbra': penumbra,
+      'falloff': falloff
+    }
+  }
+
+def rect_light(color, intensity, position, u, v, samples=16, visible=False,
+               falloff=falloff()):
+  return {
+    'Rectangle': {
+      'color': color,
+      'intensity': intensity,
+      'position': position,
+      'u': u,
+      'v': v,
+      'falloff': falloff,
+      'samples': samples,
+      'visible': visible
+    }
+  }
+
+def disk_light(color, intensity, position, normal, radius, samples=16,
+               visible=False, falloff=falloff()):
+  return {
+    'Disk': {
+      'color': color,
+      'intensity': intensity,
+      'position': position,
+      'normal': normal,
+      'radius': radius,
+      'falloff': falloff,
+      'samples': samples,
+      'visible': visible
+    }
+  }
+
+def sphere_light(color, intensity, position, radius, samples=16, visible=False,
+                 falloff=falloff()):
+  return {
+    'Sphere': {
+      'color': color,
+      'intensity': intensity,
+      'position': position,
+      'radius': radius,
+      'falloff': falloff,
+      'samples': samples,
+      'visible': visible
+    }
+  }
+
+def path_tracer(samples=16, roulette_depth=3):
+  return {
+    'PathTracer': {
+      'samples': samples,
+      'roulette_depth': roulette_depth
+    }
+  }
+
+def pbr_material(base_color, metallic=0.0, roughness=0.5, k_ambient=0.1):
+  return {
+    'k_ambient': k_ambient,
+    'pbr': {
+      'base_color': base_color,
+      'metallic': metallic,
+      'roughness': roughness
+    }
+  }
@@ -1,17 +1,7 @@
 from raylang import *
 from math import pi, cos, sin
 
-light_fire = {
-    'color'      : 'Orange',
-    'intensity'  : 2.0,
-    'position'   : [0.0, 1.0, 0.0]
-}
-
-light0 = {
-    'color'    : 'White',
-    'intensity': 0.5,
-    'position' : [4.0, 4.0, 9.0]
-}
+light0 = point_light('White', 0.5, [4.0, 4.0, 9.0])
 
 mat_default = {
     'k_diffuse'  : 0.7,
@@ -30,9 +20,12 @@ mat_bunny['color'] = 'CornflowerBlue'
 mat_fire     = {
     'k_diffuse': 0.8,
     'k_specular': 0.1,
-    'k_ambient': 0.5,
+    'k_ambient': 0.1,
     'n_shininess': 5,
-    'color': 'Orange'
+    'color': 'Orange',
+    'emission': 'Orange',
+    'emission_strength': 2.0,
+    'light_samples': 16
     }
 
 mat_frog = {
@@ -73,7 +66,7 @@ scene = {
     },
 
     'default_material' : mat_default,
-    'lights': [light0, light_fire],
+    'lights': [light0],
 
     'objects': group([
       material(mat_rocks,
@@ -0,0 +1,209 @@
+//! Adaptive supersampling driven by sample variance and pixel contrast
+
+use math::Vec3f;
+use region::Rect;
+
+/**
+ * Settings for adaptive supersampling.  Every pixel starts with one batch
+ * of samples from the scene's sampler, and further batches are added while
+ * the standard error of the pixel's mean luminance is above the threshold,
+ * up to max_samples.  Pixels whose first estimate differs from a neighbor's
+ * by more than the contrast threshold, if one is given, are sampled up to
+ * the maximum as well.
+ */
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub struct Adaptive {
+    #[serde(default = "default_max_samples")]
+    pub max_samples: u32,
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    #[serde(default)]
+    pub contrast: Option<f32>,
+}
+
+fn default_max_samples() -> u32 {
+    64
+}
+
+fn default_threshold() -> f32 {
+    0.01
+}
+
+/// A traced sample's image position and color
+pub type Sample = (f32, f32, Vec3f);
+
+/// Running luminance statistics of the samples in one pixel
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelStats {
+    count: usize,
+    sum: f32,
+    sum_squares: f32,
+}
+
+impl PixelStats {
+    fn add(&mut self, batch: &[Sample]) {
+        for &(_, _, color) in batch {
+            let l = luminance(color);
+            self.sum += l;
+            self.sum_squares += l * l;
+        }
+        self.count += batch.len();
+    }
+
+    fn mean(&self) -> f32 {
+        self.sum / self.count.max(1) as f32
+    }
+
+    /// Standard error of the mean, which is unknown for fewer than 2 samples
+    fn std_error(&self) -> f32 {
+        let n = self.count as f32;
+        if n < 2.0 {
+            return f32::INFINITY;
+        }
+        let variance = ((self.sum_squares - self.sum * self.sum / n) / (n - 1.0)).max(0.0);
+        (variance / n).sqrt()
+    }
+}
+
+fn luminance(c: Vec3f) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+/**
+ * Statistics of the first batch of samples in every pixel of the image.
+ * They are gathered for the whole image before any pixel is refined, so
+ * that pixels on the edge of a tile are compared with their neighbors in
+ * other tiles.
+ */
+#[derive(Debug, Clone)]
+pub struct Estimates {
+    image: Rect,
+    pixels: Vec<PixelStats>,
+}
+
+impl Estimates {
+    pub fn new(image: Rect) -> Estimates {
+        Estimates {
+            image,
+            pixels: vec![PixelStats::default(); (image.width * image.height) as usize],
+        }
+    }
+
+    /// Record the first batch of each pixel of the tile, in the tile's pixel order
+    pub fn add_tile(&mut self, tile: Rect, batches: &[Vec<Sample>]) {
+        for ((x, y), batch) in tile.pixels().zip(batches) {
+            let i = self.index(x, y);
+            self.pixels[i].add(batch);
+        }
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        ((y - self.image.y) * self.image.width + x - self.image.x) as usize
+    }
+
+    /// Statistics of the pixels beside (x, y) within the image
+    fn neighbors(&self, x: u32, y: u32) -> impl Iterator<Item = &PixelStats> {
+        let (x, y) = (x as i64 - self.image.x as i64, y as i64 - self.image.y as i64);
+        let (w, h) = (self.image.width as i64, self.image.height as i64);
+        IntoIterator::into_iter([(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)])
+            .filter(move |&(nx, ny)| nx >= 0 && nx < w && ny >= 0 && ny < h)
+            .map(move |(nx, ny)| &self.pixels[(ny * w + nx) as usize])
+    }
+}
+
+impl Adaptive {
+    /**
+     * Continue sampling each pixel of the tile from its first batch, where
+     * trace(x, y, batch) traces the given batch of samples for a pixel.
+     * Returns the samples added to the first batches.
+     */
+    pub fn refine_tile<F>(&self, tile: Rect, estimates: &Estimates, trace: F) -> Vec<Sample>
+    where F: Fn(u32, u32, u32) -> Vec<Sample> {
+        let max_samples = self.max_samples as usize;
+        let mut samples = vec![];
+        for (x, y) in tile.pixels() {
+            let mut stats = estimates.pixels[estimates.index(x, y)];
+            let mean = stats.mean();
+            let edge = self.contrast.is_some_and(|contrast| {
+                estimates.neighbors(x, y).any(|n| (mean - n.mean()).abs() > contrast)
+            });
+            let mut batch = 1;
+            while stats.count < max_samples && (edge || stats.std_error() > self.threshold) {
+                let mut added = trace(x, y, batch);
+                added.truncate(max_samples - stats.count);
+                if added.is_empty() {
+                    break;
+                }
+                stats.add(&added);
+                samples.extend(added);
+                batch += 1;
+            }
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADAPTIVE: Adaptive = Adaptive { max_samples: 32, threshold: 0.01, contrast: None };
+
+    /// Trace 4 samples per batch, alternating between colors a and b
+    fn batch(x: u32, y: u32, a: f32, b: f32) -> Vec<Sample> {
+        (0..4).map(|i| {
+            let c = if i % 2 == 0 { a } else { b };
+            (x as f32, y as f32, Vec3f::new(c, c, c))
+        }).collect()
+    }
+
+    /// Sample the image in tiles of the given size, returning every sample
+    fn sample<F>(adaptive: &Adaptive, image: Rect, tile_size: u32, trace: F) -> Vec<Sample>
+    where F: Fn(u32, u32, u32) -> Vec<Sample> {
+        let tiles = image.tiles(tile_size);
+        let mut estimates = Estimates::new(image);
+        let mut samples = vec![];
+        for &tile in &tiles {
+            let batches: Vec<_> = tile.pixels().map(|(x, y)| trace(x, y, 0)).collect();
+            estimates.add_tile(tile, &batches);
+            samples.extend(batches.concat());
+        }
+        for &tile in &tiles {
+            samples.extend(adaptive.refine_tile(tile, &estimates, &trace));
+        }
+        samples
+    }
+
+    #[test]
+    fn test_flat_pixels_stop_early() {
+        let samples = sample(&ADAPTIVE, Rect::image(4, 4), 4, |x, y, _| batch(x, y, 0.5, 0.5));
+        assert_eq!(samples.len(), 16 * 4);
+    }
+
+    #[test]
+    fn test_noisy_pixels_refined() {
+        let samples = sample(&ADAPTIVE, Rect::image(4, 4), 2, |x, y, _| {
+            if x == 2 && y == 1 { batch(x, y, 0.0, 1.0) } else { batch(x, y, 0.2, 0.2) }
+        });
+        assert_eq!(samples.len(), 15 * 4 + 32);
+        let noisy = samples.iter().filter(|s| s.0 == 2.0 && s.1 == 1.0).count();
+        assert_eq!(noisy, 32);
+    }
+
+    #[test]
+    fn test_contrast_refines_edges() {
+        let image = Rect { x: 10, y: 10, width: 4, height: 1 };
+        let adaptive = Adaptive { contrast: Some(0.1), ..ADAPTIVE };
+        let edge = |x: u32, y: u32, _| {
+            let c = if x < 12 { 0.0 } else { 1.0 };
+            batch(x, y, c, c)
+        };
+        // Pixels 11 and 12 lie on the edge, whether or not a tile boundary
+        // runs between them
+        for &tile_size in &[4, 2] {
+            let samples = sample(&adaptive, image, tile_size, edge);
+            assert_eq!(samples.len(), 2 * 4 + 2 * 32);
+        }
+        assert_eq!(Estimates::new(image).neighbors(10, 10).count(), 1);
+    }
+}
@@ -1,8 +1,8 @@
-use math::Vec3f;
+use math::{Vec3f, Mat4f};
 use ray_tracer::Ray;
 use std::f32;
 
-#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
 pub struct BoundingBox {
     pub min: Vec3f,
     pub max: Vec3f,
@@ -10,24 +10,128 @@ pub struct BoundingBox {
 
 impl BoundingBox {
     /**
-     * Determine if the ray intersects the bounding box.
+     * Determine if the ray intersects the bounding box, returning the
+     * distance at which it enters the box, or zero if it starts inside.
      *
      * For all 3 axes, calculate the intersection distances t1 and t2.
      */
-    pub fn intersect(&self, ray: Ray) -> bool {
+    pub fn intersect(&self, ray: Ray) -> Option<f32> {
         let (t1x, t2x) = get_t1_t2(ray.origin.x, ray.direction.x, self.min.x, self.max.x);
         let (t1y, t2y) = get_t1_t2(ray.origin.y, ray.direction.y, self.min.y, self.max.y);
         let (t1z, t2z) = get_t1_t2(ray.origin.z, ray.direction.z, self.min.z, self.max.z);
         let t_near = t1x.max(t1y.max(t1z));
         let t_far  = t2x.min(t2y.min(t2z));
         if (t_near > t_far) || t_far < 0.0 {
-            false
+            None
         } else {
-            true
+            Some(t_near.max(0.0))
         }
     }
 
-    pub fn from_vertices(vertices: &Vec<Vec3f>) -> BoundingBox {
+    /**
+     * Entry distance of a ray given the reciprocal of its direction, for
+     * BVH traversal.  Boxes entered beyond t_max are missed.
+     */
+    pub fn entry_distance(&self, origin: Vec3f, inv_dir: Vec3f, t_max: f32) -> Option<f32> {
+        if self.is_empty() {
+            return None;
+        }
+        let slab = |min: f32, max: f32, o: f32, inv: f32| ((min - o) * inv, (max - o) * inv);
+        let (t1x, t2x) = slab(self.min.x, self.max.x, origin.x, inv_dir.x);
+        let (t1y, t2y) = slab(self.min.y, self.max.y, origin.y, inv_dir.y);
+        let (t1z, t2z) = slab(self.min.z, self.max.z, origin.z, inv_dir.z);
+        let t_near = t1x.min(t2x).max(t1y.min(t2y)).max(t1z.min(t2z)).max(0.0);
+        let t_far  = t1x.max(t2x).min(t1y.max(t2y)).min(t1z.max(t2z)).min(t_max);
+        if t_near <= t_far {
+            Some(t_near)
+        } else {
+            None
+        }
+    }
+
+    /// A box bounding unbounded objects such as planes
+    pub fn infinite() -> BoundingBox {
+        BoundingBox {
+            min: Vec3f::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            max: Vec3f::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+        }
+    }
+
+    pub fn is_finite(&self) -> bool {
+        [self.min.x, self.min.y, self.min.z, self.max.x, self.max.y, self.max.z]
+            .iter().all(|v| v.is_finite())
+    }
+
+    /// Whether the box is inverted on any axis, so that it contains nothing
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    /// An inverted box which acts as the identity for union
+    pub fn empty() -> BoundingBox {
+        BoundingBox {
+            min: Vec3f::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Vec3f::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            min: Vec3f::new(self.min.x.min(other.min.x),
+                            self.min.y.min(other.min.y),
+                            self.min.z.min(other.min.z)),
+            max: Vec3f::new(self.max.x.max(other.max.x),
+                            self.max.y.max(other.max.y),
+                            self.max.z.max(other.max.z)),
+        }
+    }
+
+    /// The overlap of two boxes, which is empty if they are disjoint
+    pub fn intersection(&self, other: &BoundingBox) -> BoundingBox {
+        let overlap = BoundingBox {
+            min: Vec3f::new(self.min.x.max(other.min.x),
+                            self.min.y.max(other.min.y),
+                            self.min.z.max(other.min.z)),
+            max: Vec3f::new(self.max.x.min(other.max.x),
+                            self.max.y.min(other.max.y),
+                            self.max.z.min(other.max.z)),
+        };
+        if overlap.is_empty() {
+            BoundingBox::empty()
+        } else {
+            overlap
+        }
+    }
+
+    /// Bound the box's eight corners after transformation
+    pub fn transform(&self, t: &Mat4f) -> BoundingBox {
+        if !self.is_finite() {
+            return BoundingBox::infinite();
+        }
+        let (lo, hi) = (self.min, self.max);
+        let corners: Vec<Vec3f> = (0..8).map(|i| {
+            let x = if i & 1 == 0 { lo.x } else { hi.x };
+            let y = if i & 2 == 0 { lo.y } else { hi.y };
+            let z = if i & 4 == 0 { lo.z } else { hi.z };
+            t.transform_point(Vec3f::new(x, y, z))
+        }).collect();
+        BoundingBox::from_vertices(&corners)
+    }
+
+    pub fn centroid(&self) -> Vec3f {
+        (self.min + self.max).scale(0.5)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            0.0
+        } else {
+            2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+        }
+    }
+
+    pub fn from_vertices(vertices: &[Vec3f]) -> BoundingBox {
         let (min_x, max_x) = vec3f_min_max(vertices, |v| v.x);
         let (min_y, max_y) = vec3f_min_max(vertices, |v| v.y);
         let (min_z, max_z) = vec3f_min_max(vertices, |v| v.z);
@@ -48,7 +152,7 @@ fn get_t1_t2(ray_origin_x: f32, ray_dir_x: f32, min_x: f32, max_x: f32) -> (f32,
     }
 }
 
-fn vec3f_min_max<F>(vs: &Vec<Vec3f>, axis_fn: F) -> (f32, f32)
+fn vec3f_min_max<F>(vs: &[Vec3f], axis_fn: F) -> (f32, f32)
 where F: Fn(&Vec3f) -> f32 {
     let xs: Vec<f32> = vs.iter().map(axis_fn).collect();
     let min_x = xs.iter().cloned().fold(f32::MAX, f32::min);
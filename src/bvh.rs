@@ -0,0 +1,474 @@
+//! Bounding volume hierarchies built with the surface area heuristic
+
+use std::f32;
+use math::Vec3f;
+use ray_tracer::Ray;
+use bounding_box::BoundingBox;
+
+/// Number of candidate split planes tried along each axis
+const BINS: usize = 16;
+/// Estimated cost of visiting an inner node, relative to one item intersection
+const TRAVERSAL_COST: f32 = 1.0;
+/// Maximum tree depth, which bounds the traversal stack
+const MAX_DEPTH: usize = 64;
+
+/**
+ * Hierarchy over items identified by their index into the bounds the tree
+ * was built from.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum BvhTree {
+    Leaf {
+        bbox: BoundingBox,
+        items: Vec<usize>,
+    },
+    Node {
+        bbox: BoundingBox,
+        left: Box<BvhTree>,
+        right: Box<BvhTree>,
+    },
+}
+
+/// Summary of the shape and estimated cost of a tree
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BvhStats {
+    pub leaves: usize,
+    pub depth: usize,
+    pub sah_cost: f32,
+}
+
+impl BvhStats {
+    /// Totals over two trees, keeping the greater depth
+    pub fn add(&self, other: &BvhStats) -> BvhStats {
+        BvhStats {
+            leaves: self.leaves + other.leaves,
+            depth: self.depth.max(other.depth),
+            sah_cost: self.sah_cost + other.sah_cost,
+        }
+    }
+}
+
+impl BvhTree {
+    /**
+     * Build a tree with binned SAH.  Each node tries BINS split planes on
+     * every axis and keeps the one with the lowest estimated cost, becoming a
+     * leaf instead when that is cheaper and it holds at most leaf_limit items.
+     */
+    pub fn build(bounds: &[BoundingBox], leaf_limit: usize) -> BvhTree {
+        let centroids: Vec<[f32; 3]> = bounds.iter().map(|b| {
+            let c = b.centroid();
+            [c.x, c.y, c.z]
+        }).collect();
+        let mut items: Vec<usize> = (0..bounds.len()).collect();
+        build_node(bounds, &centroids, &mut items, leaf_limit.max(1), 1)
+    }
+
+    pub fn bbox(&self) -> &BoundingBox {
+        match *self {
+            BvhTree::Leaf { ref bbox, .. } | BvhTree::Node { ref bbox, .. } => bbox,
+        }
+    }
+
+    /**
+     * Count the leaves and levels of the tree, and estimate the cost of
+     * intersecting it as the SAH cost of each node weighted by the chance
+     * that a ray through the root also passes through the node.
+     */
+    pub fn stats(&self) -> BvhStats {
+        let root_area = self.bbox().surface_area();
+        self.stats_below(root_area)
+    }
+
+    fn stats_below(&self, root_area: f32) -> BvhStats {
+        // A root without area, such as one bounding a single point, is
+        // passed through by the same rays as every node below it
+        let area = if root_area > 0.0 { self.bbox().surface_area() / root_area } else { 1.0 };
+        match *self {
+            BvhTree::Leaf { ref items, .. } => BvhStats {
+                leaves: 1,
+                depth: 1,
+                sah_cost: area * items.len() as f32,
+            },
+            BvhTree::Node { ref left, ref right, .. } => {
+                let (l, r) = (left.stats_below(root_area), right.stats_below(root_area));
+                BvhStats {
+                    leaves: l.leaves + r.leaves,
+                    depth: 1 + l.depth.max(r.depth),
+                    sah_cost: area * TRAVERSAL_COST + l.sah_cost + r.sah_cost,
+                }
+            },
+        }
+    }
+}
+
+/**
+ * Node of a flattened BVH.  Nodes are stored depth first, so the first child
+ * of an inner node immediately follows it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BvhNode {
+    bbox: BoundingBox,
+    kind: NodeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NodeKind {
+    /// Range of items covered by a leaf
+    Leaf { first: u32, count: u32 },
+    /// Inner node, whose first child follows it
+    Inner { second: u32 },
+}
+
+/**
+ * Flattened BVH.  Each leaf covers a contiguous range of items, so the items
+ * must be stored in the order returned by Bvh::new.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+}
+
+impl Bvh {
+    /**
+     * Flatten the tree, returning the order in which to store the items.  A
+     * tree without items gives a BVH without nodes, which every ray misses.
+     */
+    pub fn new(tree: &BvhTree) -> (Bvh, Vec<usize>) {
+        let mut bvh = Bvh { nodes: vec![] };
+        let mut order = vec![];
+        match *tree {
+            BvhTree::Leaf { ref items, .. } if items.is_empty() => {},
+            _ => bvh.flatten(tree, &mut order),
+        }
+        (bvh, order)
+    }
+
+    fn flatten(&mut self, tree: &BvhTree, order: &mut Vec<usize>) {
+        match *tree {
+            BvhTree::Leaf { bbox, ref items } => {
+                let (first, count) = (order.len() as u32, items.len() as u32);
+                self.nodes.push(BvhNode { bbox, kind: NodeKind::Leaf { first, count } });
+                order.extend(items);
+            },
+            BvhTree::Node { bbox, ref left, ref right } => {
+                let index = self.nodes.len();
+                self.nodes.push(BvhNode { bbox, kind: NodeKind::Inner { second: 0 } });
+                self.flatten(left, order);
+                self.nodes[index].kind = NodeKind::Inner { second: self.nodes.len() as u32 };
+                self.flatten(right, order);
+            },
+        }
+    }
+
+    /// Whether the BVH has no nodes, as before it is built
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn bbox(&self) -> BoundingBox {
+        self.nodes.first().map_or(BoundingBox::empty(), |n| n.bbox)
+    }
+
+    /**
+     * Visit the items whose boxes the ray enters before t_max, nearest
+     * subtree first.  The visitor receives the current t_max and returns it,
+     * shortened if it found a closer hit, so that farther subtrees are culled.
+     */
+    pub fn traverse<F>(&self, ray: Ray, t_max: f32, mut visit: F)
+    where F: FnMut(usize, f32) -> f32 {
+        self.search(ray, t_max, |i, t_max| Some(visit(i, t_max)));
+    }
+
+    /// Determine if hit accepts any item whose box the ray enters before t_max
+    pub fn any<F>(&self, ray: Ray, t_max: f32, mut hit: F) -> bool
+    where F: FnMut(usize) -> bool {
+        self.search(ray, t_max, |i, t_max| if hit(i) { None } else { Some(t_max) })
+    }
+
+    /**
+     * Front to back traversal shared by the queries above.  The visitor
+     * returns the new t_max, or None to stop, in which case true is returned.
+     */
+    fn search<F>(&self, ray: Ray, t_max: f32, mut visit: F) -> bool
+    where F: FnMut(usize, f32) -> Option<f32> {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let origin = ray.origin;
+        let d = ray.direction;
+        let inv_dir = Vec3f::new(1.0 / d.x, 1.0 / d.y, 1.0 / d.z);
+        let mut t_max = t_max;
+        if self.nodes[0].bbox.entry_distance(origin, inv_dir, t_max).is_none() {
+            return false;
+        }
+
+        let mut stack = [(0usize, 0.0f32); MAX_DEPTH];
+        let mut top = 0;
+        let mut index = 0;
+        loop {
+            match self.nodes[index].kind {
+                NodeKind::Leaf { first, count } => {
+                    let first = first as usize;
+                    for item in first..first + count as usize {
+                        match visit(item, t_max) {
+                            Some(t) => t_max = t,
+                            None => return true,
+                        }
+                    }
+                },
+                NodeKind::Inner { second } => {
+                    let (left, right) = (index + 1, second as usize);
+                    let t_left = self.nodes[left].bbox.entry_distance(origin, inv_dir, t_max);
+                    let t_right = self.nodes[right].bbox.entry_distance(origin, inv_dir, t_max);
+                    match (t_left, t_right) {
+                        (Some(tl), Some(tr)) => {
+                            let (near, far, t_far) = if tl <= tr {
+                                (left, right, tr)
+                            } else {
+                                (right, left, tl)
+                            };
+                            stack[top] = (far, t_far);
+                            top += 1;
+                            index = near;
+                            continue;
+                        },
+                        (Some(_), None) => {
+                            index = left;
+                            continue;
+                        },
+                        (None, Some(_)) => {
+                            index = right;
+                            continue;
+                        },
+                        (None, None) => {},
+                    }
+                },
+            }
+
+            // Resume with the nearest deferred subtree which is still in range
+            loop {
+                if top == 0 {
+                    return false;
+                }
+                top -= 1;
+                let (next, t_entry) = stack[top];
+                if t_entry <= t_max {
+                    index = next;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Bin {
+    bbox: BoundingBox,
+    count: usize,
+}
+
+fn build_node(bounds: &[BoundingBox], centroids: &[[f32; 3]], items: &mut [usize],
+              leaf_limit: usize, depth: usize) -> BvhTree {
+    let bbox = items.iter().fold(BoundingBox::empty(), |b, &i| b.union(&bounds[i]));
+    let leaf = |items: &[usize], bbox: BoundingBox| BvhTree::Leaf { bbox, items: items.to_vec() };
+    if items.len() <= 1 || depth >= MAX_DEPTH {
+        return leaf(items, bbox);
+    }
+
+    let mut c_min = [f32::MAX; 3];
+    let mut c_max = [f32::MIN; 3];
+    for &i in items.iter() {
+        for axis in 0..3 {
+            c_min[axis] = c_min[axis].min(centroids[i][axis]);
+            c_max[axis] = c_max[axis].max(centroids[i][axis]);
+        }
+    }
+
+    // Find the cheapest split plane (axis, bin boundary) over all axes
+    let area = bbox.surface_area();
+    let mut best: Option<(f32, usize, usize)> = None;
+    for axis in 0..3 {
+        let extent = c_max[axis] - c_min[axis];
+        if extent <= 0.0 {
+            continue;
+        }
+        let mut bins = vec![Bin { bbox: BoundingBox::empty(), count: 0 }; BINS];
+        for &i in items.iter() {
+            let b = bin_index(centroids[i][axis], c_min[axis], extent);
+            bins[b].bbox = bins[b].bbox.union(&bounds[i]);
+            bins[b].count += 1;
+        }
+
+        // Sweep from the right to accumulate the cost of each right side
+        let mut right_cost = [0.0; BINS];
+        let (mut acc, mut count) = (BoundingBox::empty(), 0);
+        for b in (1..BINS).rev() {
+            acc = acc.union(&bins[b].bbox);
+            count += bins[b].count;
+            right_cost[b] = if count > 0 { acc.surface_area() * count as f32 } else { 0.0 };
+        }
+        let (mut acc, mut count) = (BoundingBox::empty(), 0);
+        for b in 0..BINS - 1 {
+            acc = acc.union(&bins[b].bbox);
+            count += bins[b].count;
+            if count == 0 || count == items.len() {
+                continue;
+            }
+            let left_cost = acc.surface_area() * count as f32;
+            let cost = TRAVERSAL_COST + (left_cost + right_cost[b + 1]) / area;
+            if best.is_none_or(|(c, _, _)| cost < c) {
+                best = Some((cost, axis, b + 1));
+            }
+        }
+    }
+
+    let leaf_cost = items.len() as f32;
+    let (axis, split) = match best {
+        Some((cost, _, _)) if cost >= leaf_cost && items.len() <= leaf_limit => {
+            return leaf(items, bbox);
+        },
+        Some((_, axis, split)) => (axis, split),
+        None => {
+            // All centroids coincide, so no plane separates the items
+            if items.len() <= leaf_limit {
+                return leaf(items, bbox);
+            }
+            let mid = items.len() / 2;
+            let (l, r) = items.split_at_mut(mid);
+            return BvhTree::Node {
+                bbox,
+                left: Box::new(build_node(bounds, centroids, l, leaf_limit, depth + 1)),
+                right: Box::new(build_node(bounds, centroids, r, leaf_limit, depth + 1)),
+            };
+        },
+    };
+
+    let extent = c_max[axis] - c_min[axis];
+    let mid = partition(items, |&i| bin_index(centroids[i][axis], c_min[axis], extent) < split);
+    let (l, r) = items.split_at_mut(mid);
+    BvhTree::Node {
+        bbox,
+        left: Box::new(build_node(bounds, centroids, l, leaf_limit, depth + 1)),
+        right: Box::new(build_node(bounds, centroids, r, leaf_limit, depth + 1)),
+    }
+}
+
+fn bin_index(c: f32, min: f32, extent: f32) -> usize {
+    (((c - min) / extent * BINS as f32) as usize).min(BINS - 1)
+}
+
+/// Reorder items so that those satisfying the predicate come first
+fn partition<F>(items: &mut [usize], pred: F) -> usize
+where F: Fn(&usize) -> bool {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use math::Vec3f;
+
+    fn collect_items(tree: &BvhTree, items: &mut Vec<usize>) {
+        match *tree {
+            BvhTree::Leaf { items: ref leaf, .. } => items.extend(leaf),
+            BvhTree::Node { ref left, ref right, .. } => {
+                collect_items(left, items);
+                collect_items(right, items);
+            },
+        }
+    }
+
+    fn grid() -> Vec<BoundingBox> {
+        (0..100).map(|i| {
+            let min = Vec3f::new((i % 10) as f32, (i / 10) as f32, 0.0);
+            BoundingBox { min, max: min + Vec3f::new(0.5, 0.5, 0.5) }
+        }).collect()
+    }
+
+    #[test]
+    fn test_build() {
+        let bounds = grid();
+        let tree = BvhTree::build(&bounds, 4);
+        let mut items = vec![];
+        collect_items(&tree, &mut items);
+        items.sort();
+        assert_eq!(items, (0..100).collect::<Vec<usize>>());
+        assert_eq!(tree.bbox(), &BoundingBox {
+            min: Vec3f::zero(),
+            max: Vec3f::new(9.5, 9.5, 0.5),
+        });
+        let stats = tree.stats();
+        assert!(stats.leaves >= 25);
+        assert!(stats.sah_cost < 100.0);
+    }
+
+    #[test]
+    fn test_empty() {
+        let (bvh, order) = Bvh::new(&BvhTree::build(&[], 4));
+        assert!(bvh.is_empty());
+        assert!(order.is_empty());
+        let ray = Ray { origin: Vec3f::zero(), direction: Vec3f::new(1.0, 0.0, 0.0) };
+        bvh.traverse(ray, f32::INFINITY, |_, _| panic!("Visited an item of an empty BVH"));
+        assert!(!bvh.any(ray, f32::INFINITY, |_| true));
+        let inv_dir = Vec3f::new(1.0, f32::INFINITY, f32::INFINITY);
+        assert_eq!(BoundingBox::empty().entry_distance(ray.origin, inv_dir, f32::INFINITY), None);
+    }
+
+    #[test]
+    fn test_traverse() {
+        let bounds = grid();
+        let (bvh, order) = Bvh::new(&BvhTree::build(&bounds, 2));
+        let ray = Ray {
+            origin: Vec3f::new(-1.0, 3.25, 0.25),
+            direction: Vec3f::new(1.0, 0.0, 0.0),
+        };
+
+        let mut visited = vec![];
+        bvh.traverse(ray, f32::INFINITY, |i, t_max| {
+            visited.push(order[i]);
+            t_max
+        });
+        visited.sort();
+        visited.dedup();
+        assert_eq!(visited, (30..40).collect::<Vec<usize>>());
+
+        // Hits shorten t_max, so only the nearest row of boxes is visited
+        let mut nearest = None;
+        let mut visits = 0;
+        bvh.traverse(ray, f32::INFINITY, |i, t_max| {
+            visits += 1;
+            let t = bounds[order[i]].min.x - ray.origin.x;
+            if t < t_max {
+                nearest = Some(order[i]);
+                t
+            } else {
+                t_max
+            }
+        });
+        assert_eq!(nearest, Some(30));
+        assert!(visits <= 4);
+
+        let mut visits = 0;
+        bvh.traverse(ray, 0.5, |_, t_max| {
+            visits += 1;
+            t_max
+        });
+        assert_eq!(visits, 0);
+
+        let mut visits = 0;
+        assert!(bvh.any(ray, f32::INFINITY, |i| {
+            visits += 1;
+            order[i] % 10 >= 5
+        }));
+        assert!(visits <= 10);
+        assert!(!bvh.any(ray, 5.0, |i| order[i] % 10 >= 5));
+        assert!(!bvh.any(ray, f32::INFINITY, |i| order[i] < 30));
+    }
+}
@@ -0,0 +1,217 @@
+//! Pixel reconstruction filters and the buffer samples are splatted into
+
+use std::f32::consts::PI;
+use math::Vec3f;
+use region::Rect;
+
+/**
+ * Separable reconstruction filter.  Each sample contributes to every pixel
+ * center within the filter radius, weighted by the filter on both axes.
+ */
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum Filter {
+    /// Equal weights, which with a radius of half a pixel is a plain average
+    #[default]
+    Box,
+    /// Weights falling linearly to zero at the radius
+    Tent,
+    /// Gaussian with a standard deviation of half the radius, shifted to
+    /// reach zero at the radius
+    Gaussian,
+    /// Mitchell-Netravali cubic with B = C = 1/3
+    Mitchell,
+    /// Sinc windowed by a sinc as wide as the radius
+    Lanczos,
+}
+
+impl Filter {
+    pub fn default_radius(&self) -> f32 {
+        match *self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell | Filter::Lanczos => 2.0,
+        }
+    }
+
+    /// Weight of a sample at distance d from the pixel center along one axis
+    pub fn evaluate(&self, d: f32, radius: f32) -> f32 {
+        let d = d.abs();
+        if d >= radius {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box => 1.0,
+            Filter::Tent => 1.0 - d / radius,
+            Filter::Gaussian => {
+                let gaussian = |x: f32| (-2.0 * (x / radius).powi(2)).exp();
+                gaussian(d) - gaussian(radius)
+            },
+            Filter::Mitchell => mitchell(2.0 * d / radius),
+            Filter::Lanczos => sinc(d) * sinc(d / radius),
+        }
+    }
+}
+
+/// Mitchell-Netravali filter with B = C = 1/3, for |x| < 2
+fn mitchell(x: f32) -> f32 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let (x2, x3) = (x * x, x * x * x);
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2
+            + (6.0 - 2.0 * b)) / 6.0
+    } else {
+        ((-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)) / 6.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/**
+ * Weighted accumulation buffer over a region of the image.  Pixel colors
+ * are the weighted sums of the samples splatted into them divided by the
+ * sums of their weights.
+ */
+#[derive(Debug, Clone)]
+pub struct Film {
+    rect: Rect,
+    sums: Vec<Vec3f>,
+    weights: Vec<f32>,
+}
+
+impl Film {
+    pub fn new(rect: Rect) -> Film {
+        let size = (rect.width * rect.height) as usize;
+        Film {
+            rect,
+            sums: vec![Vec3f::zero(); size],
+            weights: vec![0.0; size],
+        }
+    }
+
+    /**
+     * Add a sample at image position (x, y), where pixel centers lie on
+     * integer coordinates, to the pixels of the film within the radius.
+     */
+    pub fn splat(&mut self, x: f32, y: f32, color: Vec3f, filter: Filter, radius: f32) {
+        // Pixels strictly within the radius, clipped to the film
+        let rect = self.rect;
+        let range = |p: f32, min: u32, len: u32| {
+            let lo = ((p - radius).floor() as i64 + 1).max(min as i64);
+            let hi = ((p + radius).ceil() as i64 - 1).min(min as i64 + len as i64 - 1);
+            (lo..hi + 1).map(|i| i as u32)
+        };
+        for py in range(y, rect.y, rect.height) {
+            let wy = filter.evaluate(y - py as f32, radius);
+            if wy == 0.0 {
+                continue;
+            }
+            for px in range(x, rect.x, rect.width) {
+                let w = wy * filter.evaluate(x - px as f32, radius);
+                let i = self.index(px, py);
+                self.sums[i] = self.sums[i] + color.scale(w);
+                self.weights[i] += w;
+            }
+        }
+    }
+
+    /// Add the sums of another film where they overlap
+    pub fn merge(&mut self, other: &Film) {
+        for (x, y) in other.rect.pixels() {
+            if self.contains(x, y) {
+                let (i, j) = (self.index(x, y), other.index(x, y));
+                self.sums[i] = self.sums[i] + other.sums[j];
+                self.weights[i] += other.weights[j];
+            }
+        }
+    }
+
+    /// Reconstructed color of a pixel, clamped to be non-negative
+    pub fn color(&self, x: u32, y: u32) -> Vec3f {
+        let i = self.index(x, y);
+        if self.weights[i] <= 0.0 {
+            return Vec3f::zero();
+        }
+        let c = self.sums[i].scale(1.0 / self.weights[i]);
+        Vec3f::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0))
+    }
+
+    fn contains(&self, x: u32, y: u32) -> bool {
+        let rect = self.rect;
+        x >= rect.x && x < rect.x + rect.width && y >= rect.y && y < rect.y + rect.height
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        ((y - self.rect.y) * self.rect.width + x - self.rect.x) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FILTERS: [Filter; 5] =
+        [Filter::Box, Filter::Tent, Filter::Gaussian, Filter::Mitchell, Filter::Lanczos];
+
+    #[test]
+    fn test_filters() {
+        for filter in FILTERS.iter() {
+            let r = filter.default_radius();
+            assert!(filter.evaluate(0.0, r) > 0.0);
+            assert_eq!(filter.evaluate(r, r), 0.0);
+            assert_eq!(filter.evaluate(-r - 0.1, r), 0.0);
+            assert_eq!(filter.evaluate(0.3, r), filter.evaluate(-0.3, r));
+            assert!(filter.evaluate(0.1, r) >= filter.evaluate(0.4, r));
+        }
+        assert!((Filter::Tent.evaluate(0.25, 1.0) - 0.75).abs() < 1e-6);
+        assert!((mitchell(0.0) - 8.0 / 9.0).abs() < 1e-6);
+        assert!(mitchell(1.999).abs() < 1e-3);
+        assert!(Filter::Lanczos.evaluate(1.5, 2.0) < 0.0);
+    }
+
+    #[test]
+    fn test_box_film_averages() {
+        let mut film = Film::new(Rect::image(3, 2));
+        for &(dx, dy) in &[(-0.25, -0.25), (0.25, 0.25)] {
+            film.splat(1.0 + dx, 1.0 + dy, Vec3f::new(1.0, 2.0, 3.0), Filter::Box, 0.5);
+        }
+        film.splat(2.25, 0.75, Vec3f::new(3.0, 0.0, 0.0), Filter::Box, 0.5);
+        film.splat(1.75, 0.75, Vec3f::new(5.0, 0.0, 0.0), Filter::Box, 0.5);
+        assert_eq!(film.color(1, 1), Vec3f::new(1.0, 2.0, 3.0));
+        assert_eq!(film.color(2, 1), Vec3f::new(4.0, 0.0, 0.0));
+        assert_eq!(film.color(0, 0), Vec3f::zero());
+    }
+
+    #[test]
+    fn test_splat_and_merge() {
+        let image = Rect::image(8, 8);
+        let mut whole = Film::new(image);
+        // Halves of the image, each with a margin as wide as the filter
+        let mut left = Film::new(Rect { x: 0, y: 0, width: 6, height: 8 });
+        let mut right = Film::new(Rect { x: 2, y: 0, width: 6, height: 8 });
+        for &(x, y) in &[(2.2, 3.7), (3.9, 4.1), (6.5, 0.2)] {
+            let color = Vec3f::new(x, y, 1.0);
+            whole.splat(x, y, color, Filter::Gaussian, 1.5);
+            if x < 4.0 {
+                left.splat(x, y, color, Filter::Gaussian, 1.5);
+            } else {
+                right.splat(x, y, color, Filter::Gaussian, 1.5);
+            }
+        }
+        let mut merged = Film::new(image);
+        merged.merge(&left);
+        merged.merge(&right);
+        for (x, y) in image.pixels() {
+            assert!((merged.color(x, y) - whole.color(x, y)).magnitude() < 1e-5);
+        }
+        let blend = whole.color(3, 4).x;
+        assert!(blend > 2.2 && blend < 3.9);
+    }
+}
@@ -14,26 +14,42 @@ mod mesh;
 mod primitive;
 mod ray_tracer;
 mod bounding_box;
+mod bvh;
+mod region;
+mod sampler;
+mod filter;
+mod adaptive;
+mod light;
+mod microfacet;
 mod scene;
 
 use libc::c_char;
 use std::ffi::CStr;
+use std::ptr;
 use scene::Scene;
 
+/// Decode and prepare a scene, returning null if it cannot be prepared
 #[no_mangle]
 pub extern "C" fn decode_json_scene(json: *const c_char) -> *const Scene {
     unsafe {
         let json_str = CStr::from_ptr(json).to_str()
             .expect("Error converting json to str");
-        Box::into_raw(
-            Box::new(
-                Scene::decode_json(json_str).prepare()))
+        match Scene::decode_json(json_str).prepare() {
+            Ok(scene) => Box::into_raw(Box::new(scene)),
+            Err(e) => {
+                println!("Unable to prepare scene: {}", e);
+                ptr::null()
+            },
+        }
     }
 
 }
 
 #[no_mangle]
 pub extern "C" fn render(scene: *const Scene) {
+    if scene.is_null() {
+        return;
+    }
     unsafe {
         (*scene).render();
         println!("Wrote file {:?}", (*scene).image);
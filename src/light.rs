@@ -0,0 +1,591 @@
+//! Light sources and the illumination they cast on points in the scene
+
+use std::f32;
+use std::f32::consts::PI;
+use serde::{Deserialize, Deserializer};
+use color::Color;
+use math::{to_radians, Vec3f, solve_quadratic};
+use primitive::Primitive;
+use ray_tracer::Ray;
+use sampler::Rng;
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum Light {
+    /// Light radiating equally in all directions from a point
+    Point {
+        color: Color,
+        intensity: f32,
+        position: Vec3f,
+        #[serde(default)]
+        falloff: Falloff,
+    },
+    /// Distant light, such as the sun, shining along direction everywhere
+    Directional {
+        color: Color,
+        intensity: f32,
+        direction: Vec3f,
+    },
+    /**
+     * Point light restricted to a cone about direction, with angle the
+     * half-angle of the cone in degrees.  The light fades out smoothly over
+     * the outermost penumbra degrees of the cone.
+     */
+    Spot {
+        color: Color,
+        intensity: f32,
+        position: Vec3f,
+        direction: Vec3f,
+        angle: f32,
+        #[serde(default)]
+        penumbra: f32,
+        #[serde(default)]
+        falloff: Falloff,
+    },
+    /**
+     * Rectangle centered at position with edges u and v, emitting from the
+     * side that u x v faces.
+     */
+    Rectangle {
+        color: Color,
+        intensity: f32,
+        position: Vec3f,
+        u: Vec3f,
+        v: Vec3f,
+        #[serde(default)]
+        falloff: Falloff,
+        #[serde(default = "default_samples")]
+        samples: u32,
+        #[serde(default)]
+        visible: bool,
+    },
+    /// Disk centered at position, emitting from the side its normal faces
+    Disk {
+        color: Color,
+        intensity: f32,
+        position: Vec3f,
+        normal: Vec3f,
+        radius: f32,
+        #[serde(default)]
+        falloff: Falloff,
+        #[serde(default = "default_samples")]
+        samples: u32,
+        #[serde(default)]
+        visible: bool,
+    },
+    /// Sphere emitting in all directions
+    Sphere {
+        color: Color,
+        intensity: f32,
+        position: Vec3f,
+        radius: f32,
+        #[serde(default)]
+        falloff: Falloff,
+        #[serde(default = "default_samples")]
+        samples: u32,
+        #[serde(default)]
+        visible: bool,
+    },
+    /**
+     * Triangles of an emissive mesh in world space, sampled in proportion
+     * to their area.  These are made from the scene's emissive materials
+     * rather than read.
+     */
+    #[serde(skip_deserializing)]
+    Emissive {
+        triangles: Vec<[Vec3f; 3]>,
+        /// Running totals of the triangle areas
+        areas: Vec<f32>,
+        radiance: Vec3f,
+        samples: u32,
+    },
+    /// Sphere or disk primitive whose material emits light, on both sides
+    #[serde(skip_deserializing)]
+    EmissivePrimitive {
+        primitive: Primitive,
+        radiance: Vec3f,
+        samples: u32,
+    },
+}
+
+fn default_samples() -> u32 {
+    16
+}
+
+/// Lights as written in scenes, which may predate the kinds of light
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LightFormat {
+    Light(Light),
+    /// Point light as scenes described every light before there were kinds
+    Legacy {
+        color: Color,
+        intensity: f32,
+        position: Vec3f,
+    },
+}
+
+/**
+ * Read a list of lights, taking those without a kind to be point lights
+ * so that older scenes still load.
+ */
+pub fn deserialize_lights<'de, D>(deserializer: D) -> Result<Vec<Light>, D::Error>
+where D: Deserializer<'de> {
+    let lights: Vec<LightFormat> = Deserialize::deserialize(deserializer)?;
+    Ok(lights.into_iter().map(|light| match light {
+        LightFormat::Light(light) => light,
+        LightFormat::Legacy { color, intensity, position } => {
+            Light::Point { color, intensity, position, falloff: Falloff::default() }
+        },
+    }).collect())
+}
+
+/**
+ * Attenuation of a light with distance d, dividing its intensity by
+ * constant + linear * d + quadratic * d^2.
+ */
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub struct Falloff {
+    #[serde(default = "default_constant")]
+    pub constant: f32,
+    #[serde(default)]
+    pub linear: f32,
+    #[serde(default)]
+    pub quadratic: f32,
+}
+
+fn default_constant() -> f32 {
+    1.0
+}
+
+impl Default for Falloff {
+    fn default() -> Falloff {
+        Falloff { constant: 1.0, linear: 0.0, quadratic: 0.0 }
+    }
+}
+
+impl Falloff {
+    pub fn attenuation(&self, d: f32) -> f32 {
+        1.0 / (self.constant + self.linear * d + self.quadratic * d * d).max(f32::EPSILON)
+    }
+}
+
+/// Light arriving at a point from one direction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Illumination {
+    /// Unit direction from the point toward the light
+    pub direction: Vec3f,
+    /// Distance to the light, infinite for directional lights
+    pub distance: f32,
+    /// Color scaled by intensity and attenuation
+    pub radiance: Vec3f,
+}
+
+impl Light {
+    /// Light given off by a mesh's triangles, leaving out any with no area
+    pub fn emissive(triangles: Vec<[Vec3f; 3]>, radiance: Vec3f, samples: u32) -> Light {
+        let area = |&[a, b, c]: &[Vec3f; 3]| 0.5 * (b - a).cross(c - a).magnitude();
+        let triangles: Vec<[Vec3f; 3]> = triangles.into_iter().filter(|t| area(t) > 0.0).collect();
+        let mut total = 0.0;
+        let areas = triangles.iter()
+            .map(|t| {
+                total += area(t);
+                total
+            })
+            .collect();
+        Light::Emissive { triangles, areas, radiance, samples }
+    }
+
+    /// Number of shadow samples to take, which is one for point-like lights
+    pub fn samples(&self) -> u32 {
+        match *self {
+            Light::Point { .. } | Light::Directional { .. } | Light::Spot { .. } => 1,
+            Light::Rectangle { samples, .. } | Light::Disk { samples, .. } |
+            Light::Sphere { samples, .. } | Light::Emissive { samples, .. } |
+            Light::EmissivePrimitive { samples, .. } => samples.max(1),
+        }
+    }
+
+    /**
+     * Light arriving at point, or None if the light does not reach it.  Area
+     * lights are sampled at a random point, and emit as a point light of the
+     * same intensity would from there, scaled by the cosine of the angle of
+     * emission for rectangles and disks.
+     */
+    pub fn illuminate(&self, point: Vec3f, rng: &mut Rng) -> Option<Illumination> {
+        match *self {
+            Light::Point { color, intensity, position, falloff } => {
+                let (direction, distance) = toward(point, position);
+                let radiance = color.vec3f().scale(intensity * falloff.attenuation(distance));
+                Some(Illumination { direction, distance, radiance })
+            },
+            Light::Directional { color, intensity, direction } => {
+                Some(Illumination {
+                    direction: direction.norm().scale(-1.0),
+                    distance: f32::INFINITY,
+                    radiance: color.vec3f().scale(intensity),
+                })
+            },
+            Light::Spot { color, intensity, position, direction, angle, penumbra, falloff } => {
+                let (to_light, distance) = toward(point, position);
+                let cone = spot_cone(angle, penumbra, -to_light.dot(direction.norm()));
+                if cone <= 0.0 {
+                    return None;
+                }
+                let scale = intensity * falloff.attenuation(distance) * cone;
+                Some(Illumination {
+                    direction: to_light,
+                    distance,
+                    radiance: color.vec3f().scale(scale),
+                })
+            },
+            Light::Rectangle { color, intensity, position, u, v, falloff, .. } => {
+                let (r1, r2) = (rng.next_f32() - 0.5, rng.next_f32() - 0.5);
+                let sample = position + u.scale(r1) + v.scale(r2);
+                emit_from(point, sample, u.cross(v).norm(), color, intensity, falloff)
+            },
+            Light::Disk { color, intensity, position, normal, radius, falloff, .. } => {
+                let normal = normal.norm();
+                let (a, b) = normal.orthonormal_basis();
+                let (r, phi) = (radius * rng.next_f32().sqrt(), 2.0 * PI * rng.next_f32());
+                let sample = position + a.scale(r * phi.cos()) + b.scale(r * phi.sin());
+                emit_from(point, sample, normal, color, intensity, falloff)
+            },
+            Light::Sphere { color, intensity, position, radius, falloff, .. } => {
+                let (axis, center_distance) = toward(point, position);
+                if center_distance <= radius {
+                    let radiance = color.vec3f().scale(intensity * falloff.attenuation(0.0));
+                    return Some(Illumination { direction: axis, distance: 0.0, radiance });
+                }
+                // Uniform direction within the cone the sphere subtends
+                let cos_max = (1.0 - (radius / center_distance).powi(2)).sqrt();
+                let cos = 1.0 - rng.next_f32() * (1.0 - cos_max);
+                let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                let phi = 2.0 * PI * rng.next_f32();
+                let (a, b) = axis.orthonormal_basis();
+                let direction = (axis.scale(cos) + a.scale(sin * phi.cos())
+                                 + b.scale(sin * phi.sin())).norm();
+                let distance = intersect_sphere(position, radius, Ray { origin: point, direction })
+                    .unwrap_or(center_distance - radius);
+                let radiance = color.vec3f().scale(intensity * falloff.attenuation(distance));
+                Some(Illumination { direction, distance, radiance })
+            },
+            Light::Emissive { ref triangles, ref areas, radiance, .. } => {
+                let total = *areas.last()?;
+                let target = rng.next_f32() * total;
+                let i = areas.partition_point(|&a| a <= target).min(triangles.len() - 1);
+                let [a, b, c] = triangles[i];
+                let (r1, r2) = (rng.next_f32().sqrt(), rng.next_f32());
+                let sample = a.scale(1.0 - r1) + b.scale(r1 * (1.0 - r2)) + c.scale(r1 * r2);
+                let (direction, distance) = toward(point, sample);
+                // Irradiance from the whole mesh as if it were all at the
+                // sample, over pi as Phong shading expects
+                let cos = direction.dot((b - a).cross(c - a).norm()).abs();
+                let scale = cos * total / (PI * (distance * distance).max(f32::EPSILON));
+                Some(Illumination { direction, distance, radiance: radiance.scale(scale) })
+            },
+            Light::EmissivePrimitive { ref primitive, radiance, .. } => {
+                let (sample, normal, pdf) = primitive.sample_surface(rng)?;
+                let (direction, distance) = toward(point, sample);
+                let cos = direction.dot(normal).abs();
+                let scale = cos / (PI * pdf * (distance * distance).max(f32::EPSILON));
+                Some(Illumination { direction, distance, radiance: radiance.scale(scale) })
+            },
+        }
+    }
+
+    /**
+     * Distance along the ray to the shape of an area light which is visible
+     * in the image, with the color it appears.
+     */
+    pub fn intersect(&self, ray: Ray) -> Option<(f32, Vec3f)> {
+        let hit = match *self {
+            Light::Rectangle { position, u, v, visible: true, .. } => {
+                intersect_plane(position, u.cross(v), ray).filter(|&t| {
+                    let p = ray.origin + ray.direction.scale(t) - position;
+                    p.dot(u).abs() <= 0.5 * u.dot(u) && p.dot(v).abs() <= 0.5 * v.dot(v)
+                })
+            },
+            Light::Disk { position, normal, radius, visible: true, .. } => {
+                intersect_plane(position, normal, ray).filter(|&t| {
+                    let p = ray.origin + ray.direction.scale(t);
+                    (p - position).magnitude_squared() <= radius * radius
+                })
+            },
+            Light::Sphere { position, radius, visible: true, .. } => {
+                intersect_sphere(position, radius, ray)
+            },
+            _ => None,
+        };
+        hit.map(|t| (t, self.emission()))
+    }
+
+    /// Color of a visible light, as bright as its intensity
+    fn emission(&self) -> Vec3f {
+        match *self {
+            Light::Point { color, intensity, .. } | Light::Directional { color, intensity, .. } |
+            Light::Spot { color, intensity, .. } | Light::Rectangle { color, intensity, .. } |
+            Light::Disk { color, intensity, .. } | Light::Sphere { color, intensity, .. } => {
+                color.vec3f().scale(intensity)
+            },
+            Light::Emissive { radiance, .. } | Light::EmissivePrimitive { radiance, .. } => {
+                radiance
+            },
+        }
+    }
+}
+
+/// Light emitted toward point from a sample on a one-sided area light
+fn emit_from(point: Vec3f, sample: Vec3f, normal: Vec3f, color: Color, intensity: f32,
+             falloff: Falloff) -> Option<Illumination> {
+    let (direction, distance) = toward(point, sample);
+    let cos = -direction.dot(normal);
+    if cos <= 0.0 {
+        return None;
+    }
+    let radiance = color.vec3f().scale(intensity * falloff.attenuation(distance) * cos);
+    Some(Illumination { direction, distance, radiance })
+}
+
+/// Nearest distance in front of the ray to a plane through point
+fn intersect_plane(point: Vec3f, normal: Vec3f, ray: Ray) -> Option<f32> {
+    let denom = ray.direction.dot(normal);
+    if denom == 0.0 {
+        return None;
+    }
+    Some((point - ray.origin).dot(normal) / denom).filter(|&t| t >= 0.0)
+}
+
+/// Nearest distance in front of the ray to a sphere
+fn intersect_sphere(center: Vec3f, radius: f32, ray: Ray) -> Option<f32> {
+    let o_c = ray.origin - center;
+    let d = ray.direction;
+    solve_quadratic(d.dot(d) as f64, 2.0 * d.dot(o_c) as f64,
+                    (o_c.dot(o_c) - radius * radius) as f64)
+        .into_iter()
+        .map(|t| t as f32)
+        .filter(|&t| t >= 0.0)
+        .fold(None, |nearest: Option<f32>, t| Some(nearest.map_or(t, |n| n.min(t))))
+}
+
+/// Unit direction and distance from point to target
+fn toward(point: Vec3f, target: Vec3f) -> (Vec3f, f32) {
+    let d = target - point;
+    let distance = d.magnitude();
+    (d.scale(1.0 / distance), distance)
+}
+
+/**
+ * Fraction of a spot light's intensity leaving at an angle with cosine cos
+ * from its axis, fading with a smoothstep across the penumbra.
+ */
+fn spot_cone(angle: f32, penumbra: f32, cos: f32) -> f32 {
+    let cos_outer = to_radians(angle).cos();
+    let cos_inner = to_radians((angle - penumbra).max(0.0)).cos();
+    if cos >= cos_inner {
+        1.0
+    } else if cos <= cos_outer {
+        0.0
+    } else {
+        let t = (cos - cos_outer) / (cos_inner - cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use math::Mat4f;
+    use serde_json;
+
+    #[derive(Deserialize)]
+    struct Lights {
+        #[serde(deserialize_with = "deserialize_lights")]
+        lights: Vec<Light>,
+    }
+
+    #[test]
+    fn test_legacy_lights() {
+        let json = r#"{"lights": [
+            {"color": "White", "intensity": 2.0, "position": {"x": 1.0, "y": 2.0, "z": 3.0}},
+            {"Directional": {"color": "Red", "intensity": 1.0,
+                             "direction": {"x": 0.0, "y": -1.0, "z": 0.0}}}
+        ]}"#;
+        let lights = serde_json::from_str::<Lights>(json).expect("Unable to decode lights").lights;
+        assert_eq!(lights[0], Light::Point {
+            color: Color::White,
+            intensity: 2.0,
+            position: Vec3f::new(1.0, 2.0, 3.0),
+            falloff: Falloff::default(),
+        });
+        assert_eq!(lights[1], Light::Directional {
+            color: Color::Red,
+            intensity: 1.0,
+            direction: Vec3f::new(0.0, -1.0, 0.0),
+        });
+    }
+
+    #[test]
+    fn test_falloff() {
+        assert_eq!(Falloff::default().attenuation(10.0), 1.0);
+        let falloff = Falloff { constant: 1.0, linear: 0.5, quadratic: 0.25 };
+        assert_eq!(falloff.attenuation(2.0), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_directional() {
+        let light = Light::Directional {
+            color: Color::White,
+            intensity: 0.5,
+            direction: Vec3f::new(0.0, -2.0, 0.0),
+        };
+        let point = Vec3f::new(5.0, 0.0, -3.0);
+        let illumination = light.illuminate(point, &mut Rng::new(0)).unwrap();
+        assert_eq!(illumination.direction, Vec3f::new(0.0, 1.0, 0.0));
+        assert_eq!(illumination.distance, f32::INFINITY);
+        assert_eq!(illumination.radiance, Vec3f::new(0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn test_spot() {
+        let light = Light::Spot {
+            color: Color::White,
+            intensity: 1.0,
+            position: Vec3f::new(0.0, 4.0, 0.0),
+            direction: Vec3f::new(0.0, -1.0, 0.0),
+            angle: 30.0,
+            penumbra: 10.0,
+            falloff: Falloff::default(),
+        };
+        let mut rng = Rng::new(0);
+        let mut radiance = |x: f32| {
+            light.illuminate(Vec3f::new(x, 0.0, 0.0), &mut rng).map(|i| i.radiance.x)
+        };
+        assert_eq!(radiance(0.0), Some(1.0));
+        assert_eq!(radiance(1.0), Some(1.0)); // 14 degrees off axis
+        assert!(radiance(2.0).is_some_and(|r| r > 0.0 && r < 1.0)); // 26.6 degrees
+        assert_eq!(radiance(3.0), None); // 36.9 degrees
+    }
+
+    #[test]
+    fn test_area_lights() {
+        let mut rng = Rng::new(3);
+        let point = Vec3f::new(0.3, 0.0, 0.2);
+        let rectangle = Light::Rectangle {
+            color: Color::White,
+            intensity: 1.0,
+            position: Vec3f::new(0.0, 2.0, 0.0),
+            u: Vec3f::new(1.0, 0.0, 0.0),
+            v: Vec3f::new(0.0, 0.0, 1.0),
+            falloff: Falloff::default(),
+            samples: 4,
+            visible: true,
+        };
+        for _ in 0..100 {
+            let i = rectangle.illuminate(point, &mut rng).unwrap();
+            let sample = point + i.direction.scale(i.distance);
+            assert!((sample.y - 2.0).abs() < 1e-5);
+            assert!(sample.x.abs() <= 0.5 && sample.z.abs() <= 0.5);
+            assert!(i.radiance.x > 0.0 && i.radiance.x <= 1.0);
+        }
+        // Light is only emitted downward
+        assert_eq!(rectangle.illuminate(Vec3f::new(0.0, 3.0, 0.0), &mut rng), None);
+
+        let sphere = Light::Sphere {
+            color: Color::White,
+            intensity: 1.0,
+            position: Vec3f::new(0.0, 3.0, 0.0),
+            radius: 0.5,
+            falloff: Falloff::default(),
+            samples: 4,
+            visible: false,
+        };
+        for _ in 0..100 {
+            let i = sphere.illuminate(point, &mut rng).unwrap();
+            let sample = point + i.direction.scale(i.distance);
+            assert!(((sample - Vec3f::new(0.0, 3.0, 0.0)).magnitude() - 0.5).abs() < 1e-4);
+        }
+        assert_eq!(sphere.samples(), 4);
+    }
+
+    #[test]
+    fn test_visible_lights() {
+        let ray = Ray { origin: Vec3f::zero(), direction: Vec3f::new(0.0, 1.0, 0.0) };
+        let disk = |visible| Light::Disk {
+            color: Color::White,
+            intensity: 2.0,
+            position: Vec3f::new(0.1, 4.0, 0.0),
+            normal: Vec3f::new(0.0, -1.0, 0.0),
+            radius: 0.5,
+            falloff: Falloff::default(),
+            samples: 1,
+            visible,
+        };
+        assert_eq!(disk(true).intersect(ray), Some((4.0, Vec3f::new(2.0, 2.0, 2.0))));
+        assert_eq!(disk(false).intersect(ray), None);
+    }
+
+    #[test]
+    fn test_emissive() {
+        // Unit square of two triangles facing down from y = 2
+        let (a, b, c, d) = (Vec3f::new(0.0, 2.0, 0.0), Vec3f::new(1.0, 2.0, 0.0),
+                            Vec3f::new(1.0, 2.0, 1.0), Vec3f::new(0.0, 2.0, 1.0));
+        let light = Light::emissive(vec![[a, b, c], [a, c, d]], Vec3f::new(1.0, 1.0, 1.0), 8);
+        assert_eq!(light.samples(), 8);
+        let mut rng = Rng::new(5);
+        let mut total = 0.0;
+        for _ in 0..2000 {
+            let point = Vec3f::new(0.5, 0.0, 0.5);
+            let i = light.illuminate(point, &mut rng).unwrap();
+            let sample = point + i.direction.scale(i.distance);
+            assert!((sample.y - 2.0).abs() < 1e-5);
+            assert!((0.0..=1.0).contains(&sample.x) && (0.0..=1.0).contains(&sample.z));
+            total += i.radiance.x * i.direction.y;
+        }
+        // Irradiance from a small square of area 1 at distance 2 is near 1/4
+        let irradiance = PI * total / 2000.0;
+        assert!(irradiance > 0.2 && irradiance < 0.25, "{}", irradiance);
+        assert_eq!(Light::emissive(vec![], Vec3f::zero(), 1).illuminate(a, &mut rng), None);
+    }
+
+    #[test]
+    fn test_emissive_primitives() {
+        let mut rng = Rng::new(6);
+        // A disk squashed into an ellipse of area 6 pi is sampled evenly
+        let disk = Primitive::Disk {
+            center: Vec3f::zero(),
+            normal: Vec3f::new(0.0, 1.0, 0.0),
+            radius: 1.0,
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
+        }.transform(&Mat4f::scale(Vec3f::new(2.0, 1.0, 3.0))).unwrap();
+        for _ in 0..100 {
+            let (sample, normal, pdf) = disk.sample_surface(&mut rng).unwrap();
+            assert!((sample.x / 2.0).powi(2) + (sample.z / 3.0).powi(2) <= 1.0 + 1e-5);
+            assert!((normal - Vec3f::new(0.0, 1.0, 0.0)).magnitude() < 1e-5);
+            assert!((pdf * 6.0 * PI - 1.0).abs() < 1e-4, "{}", pdf);
+        }
+
+        // Irradiance from the near side of a sphere of radius 2 at distance
+        // 10 is pi (2 / 10)^2, which is over pi in Phong units
+        let sphere = Primitive::Sphere {
+            radius: 1.0,
+            center: Vec3f::zero(),
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
+        }.transform(&Mat4f::scale(Vec3f::new(2.0, 2.0, 2.0))).unwrap();
+        let light = Light::EmissivePrimitive {
+            primitive: sphere,
+            radiance: Vec3f::new(1.0, 1.0, 1.0),
+            samples: 1,
+        };
+        let point = Vec3f::new(0.0, 10.0, 0.0);
+        let n = 20000;
+        let total: f32 = (0..n)
+            .map(|_| light.illuminate(point, &mut rng).unwrap())
+            .filter(|i| (point + i.direction.scale(i.distance)).y > 0.0)
+            .map(|i| i.radiance.x * -i.direction.y)
+            .sum();
+        let irradiance = total / n as f32;
+        assert!((irradiance - 0.04).abs() < 0.002, "{}", irradiance);
+    }
+}
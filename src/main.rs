@@ -8,6 +8,7 @@ extern crate image;
 extern crate time;
 
 use std::env;
+use std::process;
 use std::fs::File;
 use std::path::Path;
 use std::io::prelude::*;
@@ -18,6 +19,13 @@ mod mesh;
 mod primitive;
 mod ray_tracer;
 mod bounding_box;
+mod bvh;
+mod region;
+mod sampler;
+mod filter;
+mod adaptive;
+mod light;
+mod microfacet;
 mod scene;
 
 use scene::Scene;
@@ -41,6 +49,13 @@ fn main() {
     } else {
         Scene::decode_json(&contents)
     }.prepare();
+    let scene = match scene {
+        Ok(scene) => scene,
+        Err(e) => {
+            println!("Unable to prepare scene: {}", e);
+            process::exit(1);
+        },
+    };
 
     scene.render();
     println!("Wrote file {:?}", scene.image);
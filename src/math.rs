@@ -2,7 +2,10 @@
 #![allow(dead_code)]
 
 use std::f32::consts;
-use std::ops::{Add, Sub};
+use std::array;
+use std::f64;
+use std::iter::{FromIterator, Take};
+use std::ops::{Add, Deref, Sub};
 
 /// Degrees to radians
 pub fn to_radians(x: f32) -> f32 {
@@ -95,6 +98,38 @@ impl Vec3f {
         self.scale(1.0 / self.magnitude())
     }
 
+    /** Two unit vectors which form an orthonormal basis with this unit vector */
+    pub fn orthonormal_basis(&self) -> (Vec3f, Vec3f) {
+        let helper = if self.x.abs() > 0.9 {
+            Vec3f::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3f::new(1.0, 0.0, 0.0)
+        };
+        let u = self.cross(helper).norm();
+        (u, self.cross(u))
+    }
+
+    /** Reflect about the normal n */
+    pub fn reflect(&self, n: Vec3f) -> Vec3f {
+        *self - n.scale(2.0 * self.dot(n))
+    }
+
+    /**
+      * Refract a unit vector through a surface with unit normal n facing
+      * against it, where eta is the ratio of refractive indices n1/n2.
+      * Returns None on total internal reflection.
+      */
+    pub fn refract(&self, n: Vec3f, eta: f32) -> Option<Vec3f> {
+        let cos_i = -self.dot(n);
+        let sin2_t = eta.powi(2) * (1.0 - cos_i.powi(2));
+        if sin2_t > 1.0 {
+            None
+        } else {
+            let cos_t = (1.0 - sin2_t).sqrt();
+            Some(self.scale(eta) + n.scale(eta * cos_i - cos_t))
+        }
+    }
+
     /**
       * Compute a partial determinant using self and b as the first two columns
       * of a 3 column matrix.  Returns a vector which can be dot-product
@@ -226,6 +261,9 @@ pub struct Mat4f {
     pub r4: Vec4f,
 }
 
+/// Smallest determinant, relative to its bound, of an invertible matrix
+const SINGULAR_TOLERANCE: f32 = 1e-6;
+
 impl Mat4f {
     pub fn new(r1: Vec4f, r2: Vec4f, r3: Vec4f, r4: Vec4f) -> Mat4f {
         Mat4f { r1: r1, r2: r2, r3: r3, r4: r4 }
@@ -297,6 +335,63 @@ impl Mat4f {
         ).transpose()
     }
 
+    /**
+     * Compute the inverse from the 2x2 sub-determinants of the upper and
+     * lower row pairs (Laplace expansion).  Fails if the matrix is singular,
+     * or so nearly so that the determinant is negligible next to the
+     * product of the row lengths of the upper-left 3x3 block, which bounds
+     * it for affine transforms however large their translation.
+     */
+    pub fn inverse(&self) -> Result<Mat4f, String> {
+        let (a, b, c, d) = (self.r1, self.r2, self.r3, self.r4);
+        let s0 = a.x * b.y - b.x * a.y;
+        let s1 = a.x * b.z - b.x * a.z;
+        let s2 = a.x * b.w - b.x * a.w;
+        let s3 = a.y * b.z - b.y * a.z;
+        let s4 = a.y * b.w - b.y * a.w;
+        let s5 = a.z * b.w - b.z * a.w;
+        let c5 = c.z * d.w - d.z * c.w;
+        let c4 = c.y * d.w - d.y * c.w;
+        let c3 = c.y * d.z - d.y * c.z;
+        let c2 = c.x * d.w - d.x * c.w;
+        let c1 = c.x * d.z - d.x * c.z;
+        let c0 = c.x * d.y - d.x * c.y;
+        let det = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
+        let linear = |r: Vec4f| Vec3f::new(r.x, r.y, r.z).magnitude();
+        let bound = linear(a) * linear(b) * linear(c);
+        if det.abs() <= SINGULAR_TOLERANCE * bound || !det.is_finite() {
+            return Err(format!("Matrix is singular: {:?}", self));
+        }
+        let inv_det = 1.0 / det;
+        Ok(Mat4f::new(
+            Vec4f::new(
+                 b.y * c5 - b.z * c4 + b.w * c3,
+                -a.y * c5 + a.z * c4 - a.w * c3,
+                 d.y * s5 - d.z * s4 + d.w * s3,
+                -c.y * s5 + c.z * s4 - c.w * s3).scale(inv_det),
+            Vec4f::new(
+                -b.x * c5 + b.z * c2 - b.w * c1,
+                 a.x * c5 - a.z * c2 + a.w * c1,
+                -d.x * s5 + d.z * s2 - d.w * s1,
+                 c.x * s5 - c.z * s2 + c.w * s1).scale(inv_det),
+            Vec4f::new(
+                 b.x * c4 - b.y * c2 + b.w * c0,
+                -a.x * c4 + a.y * c2 - a.w * c0,
+                 d.x * s4 - d.y * s2 + d.w * s0,
+                -c.x * s4 + c.y * s2 - c.w * s0).scale(inv_det),
+            Vec4f::new(
+                -b.x * c3 + b.y * c1 - b.z * c0,
+                 a.x * c3 - a.y * c1 + a.z * c0,
+                -d.x * s3 + d.y * s1 - d.z * s0,
+                 c.x * s3 - c.y * s1 + c.z * s0).scale(inv_det),
+        ))
+    }
+
+    /// The matrix which transforms surface normals, the inverse transpose
+    pub fn inverse_transpose(&self) -> Result<Mat4f, String> {
+        self.inverse().map(|m| m.transpose())
+    }
+
     pub fn transform_point(&self, point: Vec3f) -> Vec3f {
         let p = Vec4f::new(point.x, point.y, point.z, 1.0);
         let Vec4f { x, y, z, w } = self.mv_multiply(p);
@@ -306,6 +401,191 @@ impl Mat4f {
     pub fn transform_direction(&self, v: Vec3f) -> Vec3f {
         Vec3f::new(self.r1.dot3(v), self.r2.dot3(v), self.r3.dot3(v))
     }
+
+    /**
+     * Transform a surface normal, where self is the inverse transpose of
+     * the matrix applied to the surface.  The result is renormalized since
+     * non-uniform scaling changes its length.
+     */
+    pub fn transform_normal(&self, n: Vec3f) -> Vec3f {
+        self.transform_direction(n).norm()
+    }
+}
+
+/**
+ * A list of at most four values stored inline.  Roots of polynomials and the
+ * hits of a ray on a primitive are found for every ray, so they are kept off
+ * the heap.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ArrayVec<T> {
+    items: [T; 4],
+    len: usize,
+}
+
+impl<T: Copy + Default> ArrayVec<T> {
+    pub fn new() -> ArrayVec<T> {
+        ArrayVec::default()
+    }
+
+    /// Append a value, panicking if the list is full
+    pub fn push(&mut self, item: T) {
+        self.items[self.len] = item;
+        self.len += 1;
+    }
+}
+
+impl<T: Copy + Default, const N: usize> From<[T; N]> for ArrayVec<T> {
+    fn from(items: [T; N]) -> ArrayVec<T> {
+        items.iter().copied().collect()
+    }
+}
+
+impl<T> Deref for ArrayVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+}
+
+impl<T> IntoIterator for ArrayVec<T> {
+    type Item = T;
+    type IntoIter = Take<array::IntoIter<T, 4>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIterator::into_iter(self.items).take(self.len)
+    }
+}
+
+impl<T: Copy + Default> FromIterator<T> for ArrayVec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> ArrayVec<T> {
+        let mut list = ArrayVec::new();
+        list.extend(iter);
+        list
+    }
+}
+
+impl<T: Copy + Default> Extend<T> for ArrayVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+/**
+ * Real roots of c2*x^2 + c1*x + c0.
+ */
+pub fn solve_quadratic(c2: f64, c1: f64, c0: f64) -> ArrayVec<f64> {
+    if c2 == 0.0 {
+        return if c1 == 0.0 { ArrayVec::new() } else { ArrayVec::from([-c0 / c1]) };
+    }
+    let p = c1 / (2.0 * c2);
+    let q = c0 / c2;
+    let d = p * p - q;
+    if d < 0.0 {
+        ArrayVec::new()
+    } else if d == 0.0 {
+        ArrayVec::from([-p])
+    } else {
+        let sqrt_d = d.sqrt();
+        ArrayVec::from([sqrt_d - p, -sqrt_d - p])
+    }
+}
+
+/**
+ * Real roots of c3*x^3 + c2*x^2 + c1*x + c0 by Cardano's method, using the
+ * trigonometric form when there are three real roots.
+ */
+pub fn solve_cubic(c3: f64, c2: f64, c1: f64, c0: f64) -> ArrayVec<f64> {
+    if c3 == 0.0 {
+        return solve_quadratic(c2, c1, c0);
+    }
+    let (a, b, c) = (c2 / c3, c1 / c3, c0 / c3);
+
+    // Substitute x = y - a/3 to eliminate the quadratic term
+    let sq_a = a * a;
+    let p = (-sq_a / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * sq_a - a * b / 3.0 + c) / 2.0;
+    let cb_p = p * p * p;
+    let d = q * q + cb_p;
+
+    let roots = if d.abs() < 1e-12 {
+        if q.abs() < 1e-12 {
+            ArrayVec::from([0.0])
+        } else {
+            let u = (-q).cbrt();
+            ArrayVec::from([2.0 * u, -u])
+        }
+    } else if d < 0.0 {
+        let phi = (-q / (-cb_p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        ArrayVec::from([t * phi.cos(),
+                        -t * (phi + f64::consts::PI / 3.0).cos(),
+                        -t * (phi - f64::consts::PI / 3.0).cos()])
+    } else {
+        let sqrt_d = d.sqrt();
+        ArrayVec::from([(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()])
+    };
+    roots.into_iter().map(|y| y - a / 3.0).collect()
+}
+
+/**
+ * Real roots of c4*x^4 + c3*x^3 + c2*x^2 + c1*x + c0 by Ferrari's method.
+ * Each root is polished with Newton's method since the closed form loses
+ * precision when roots are close together.
+ */
+pub fn solve_quartic(c4: f64, c3: f64, c2: f64, c1: f64, c0: f64) -> ArrayVec<f64> {
+    if c4 == 0.0 {
+        return solve_cubic(c3, c2, c1, c0);
+    }
+    let (a, b, c, d) = (c3 / c4, c2 / c4, c1 / c4, c0 / c4);
+
+    // Substitute x = y - a/4 to eliminate the cubic term
+    let sq_a = a * a;
+    let p = -3.0 / 8.0 * sq_a + b;
+    let q = sq_a * a / 8.0 - a * b / 2.0 + c;
+    let r = -3.0 / 256.0 * sq_a * sq_a + sq_a * b / 16.0 - a * c / 4.0 + d;
+
+    let roots = if r.abs() < 1e-12 {
+        let mut roots = solve_cubic(1.0, 0.0, p, q);
+        roots.push(0.0);
+        roots
+    } else {
+        // Factor into quadratics with the largest root of the resolvent cubic,
+        // which keeps both quadratics real whenever the quartic has real roots
+        let z = solve_cubic(1.0, -p / 2.0, -r, r * p / 2.0 - q * q / 8.0)
+            .into_iter()
+            .fold(f64::NEG_INFINITY, f64::max);
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        if u < -1e-12 || v < -1e-12 {
+            return ArrayVec::new();
+        }
+        let u = u.max(0.0).sqrt();
+        let v = if q < 0.0 { -v.max(0.0).sqrt() } else { v.max(0.0).sqrt() };
+        let mut roots = solve_quadratic(1.0, v, z - u);
+        roots.extend(solve_quadratic(1.0, -v, z + u));
+        roots
+    };
+
+    let f = |x: f64| (((x + a) * x + b) * x + c) * x + d;
+    let df = |x: f64| ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + c;
+    roots.into_iter()
+        .map(|y| y - a / 4.0)
+        .map(|mut x| {
+            for _ in 0..2 {
+                // Near a double root the slope vanishes, so keep only steps
+                // that improve the root
+                let next = x - f(x) / df(x);
+                if f(next).abs() < f(x).abs() {
+                    x = next;
+                }
+            }
+            x
+        })
+        .collect()
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
@@ -357,6 +637,19 @@ mod test {
         assert_eq!(u.norm(), vec3f(0.26726124, 0.5345225, 0.8017837));
     }
 
+    #[test]
+    fn test_reflect_refract() {
+        let vec3f = Vec3f::new;
+        let n = vec3f(0.0, 1.0, 0.0);
+        let d = vec3f(1.0, -1.0, 0.0).norm();
+        assert_eq!(d.reflect(n), vec3f(1.0, 1.0, 0.0).norm());
+        assert_eq!(d.refract(n, 1.0), Some(d));
+        let r = d.refract(n, 1.0 / 1.5).unwrap();
+        assert!((r.magnitude() - 1.0).abs() < 1e-6);
+        assert!((r.x - d.x / 1.5).abs() < 1e-6);
+        assert_eq!(vec3f(1.0, 1.0, 0.0).norm().refract(n.scale(-1.0), 1.5), None);
+    }
+
     #[test]
     fn test_vec4f() {
         let vec4f = Vec4f::new;
@@ -394,4 +687,72 @@ mod test {
             vec4f(1354.0, 1412.0, 1470.0, 1384.0));
         assert_eq!(a.mm_multiply(&b), c);
     }
+
+    #[test]
+    fn test_mat4f_inverse() {
+        let m = Mat4f::translate(Vec3f::new(1.0, -2.0, 3.0))
+            .mm_multiply(&Mat4f::rotate(Vec3f::new(0.0, 1.0, 0.0), 30.0))
+            .mm_multiply(&Mat4f::scale(Vec3f::new(2.0, 0.5, 4.0)));
+        let p = m.mm_multiply(&m.inverse().unwrap());
+        let i = Mat4f::identity();
+        for (r, ri) in [p.r1, p.r2, p.r3, p.r4].iter().zip([i.r1, i.r2, i.r3, i.r4].iter()) {
+            assert!((*r - *ri).magnitude() < 1e-5, "{:?} != identity", p);
+        }
+        assert!(Mat4f::scale(Vec3f::new(1.0, 0.0, 1.0)).inverse().is_err());
+        // Rounding leaves flattened transforms a tiny determinant, while
+        // small or uneven scales are still invertible
+        let flat = Mat4f::rotate(Vec3f::new(0.0, 0.0, 1.0), 30.0)
+            .mm_multiply(&Mat4f::scale(Vec3f::new(1.0, 0.0, 1.0)))
+            .mm_multiply(&Mat4f::rotate(Vec3f::new(1.0, 0.0, 0.0), 40.0));
+        assert!(flat.inverse().is_err());
+        assert!(Mat4f::scale(Vec3f::new(1e-3, 1e-3, 1e-3)).inverse().is_ok());
+        assert!(Mat4f::scale(Vec3f::new(1e3, 1e-3, 1.0)).inverse().is_ok());
+        // A far translation doesn't make a small scale singular
+        for &(s, t) in [(1e-3, 2e3), (1e-2, 2e4), (0.1, 1e6)].iter() {
+            let m = Mat4f::translate(Vec3f::new(t, -t, t))
+                .mm_multiply(&Mat4f::scale(Vec3f::new(s, s, s)));
+            let inverse = m.inverse().unwrap();
+            assert!((inverse.r1.x * s - 1.0).abs() < 1e-4, "{:?}", inverse);
+        }
+    }
+
+    fn sorted(mut roots: Vec<f64>) -> Vec<f64> {
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        roots
+    }
+
+    fn assert_roots(actual: ArrayVec<f64>, expected: &[f64]) {
+        let actual = sorted(actual.to_vec());
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_solve_polynomials() {
+        assert_roots(solve_quadratic(1.0, -3.0, 2.0), &[1.0, 2.0]);
+        assert_roots(solve_quadratic(1.0, 0.0, 1.0), &[]);
+        // (x - 1)(x - 2)(x + 3)
+        assert_roots(solve_cubic(1.0, 0.0, -7.0, 6.0), &[-3.0, 1.0, 2.0]);
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(solve_quartic(1.0, -10.0, 35.0, -50.0, 24.0), &[1.0, 2.0, 3.0, 4.0]);
+        // 2(x^2 + 1)(x - 0.5)(x + 0.25)
+        assert_roots(solve_quartic(2.0, -0.5, 1.75, -0.5, -0.25), &[-0.25, 0.5]);
+        // x^4 + 1 has no real roots
+        assert_roots(solve_quartic(1.0, 0.0, 0.0, 0.0, 1.0), &[]);
+        // (x - 1)^2 (x^2 + 2x - 5), whose resolvent cubic has a double root
+        let s6 = 6.0f64.sqrt();
+        assert_roots(solve_quartic(1.0, 0.0, -8.0, 12.0, -5.0), &[-1.0 - s6, 1.0, 1.0, s6 - 1.0]);
+    }
+
+    #[test]
+    fn test_transform_normal() {
+        // The plane x + y = 1 becomes x/2 + y = 1 when x is scaled by 2
+        let m = Mat4f::scale(Vec3f::new(2.0, 1.0, 1.0));
+        let n = Vec3f::new(1.0, 1.0, 0.0).norm();
+        let expected = Vec3f::new(0.5, 1.0, 0.0).norm();
+        let actual = m.inverse_transpose().unwrap().transform_normal(n);
+        assert!((actual - expected).magnitude() < 1e-6);
+    }
 }
@@ -1,16 +1,17 @@
 //! Mesh module for reading and representing mesh objects
 
-use std::cmp::Ordering::Equal;
-use std::collections::HashSet;
+use std::f32;
 use std::str::FromStr;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::prelude::*;
 use std::path::Path;
-use math::{Vec3f, Mat4f};
+use std::sync::Arc;
+use math::Vec3f;
 use ray_tracer::{Ray, Intersection};
-use scene::{Material, ObjectTree};
+use scene::MaterialId;
 use bounding_box::BoundingBox;
+use bvh::{Bvh, BvhTree, BvhStats};
 
 #[derive(Debug, Clone, Default, Deserialize, PartialEq)]
 pub struct Face {
@@ -18,10 +19,9 @@ pub struct Face {
     bi: usize,
     ci: usize,
 
-    ab_pdet_ac: Vec3f,
-    ar_pdet_ac: Vec3f,
-    ab_pdet_ar: Vec3f,
-    det_t: f32,
+    a: Vec3f,
+    ab: Vec3f,
+    ac: Vec3f,
 }
 
 impl Face {
@@ -29,7 +29,7 @@ impl Face {
     /**
       * Read the face from a string of indexes
       */
-    fn read(s: &String) -> Face {
+    fn read(s: &str) -> Face {
         let v: Vec<usize> = s.split_whitespace()
             .filter_map(|x| usize::from_str(x).ok())
             .map(|x| x-1) // SMF indexes from 1
@@ -42,78 +42,111 @@ impl Face {
         }
     }
 
-    pub fn normal(&self, vertices: &Vec<Vec3f>) -> Vec3f {
+    pub fn normal(&self, vertices: &[Vec3f]) -> Vec3f {
         let a = vertices[self.ai];
         let b = vertices[self.bi];
         let c = vertices[self.ci];
         (a-b).cross(a-c).norm()
     }
 
+    pub fn bounding_box(&self, vertices: &[Vec3f]) -> BoundingBox {
+        BoundingBox::from_vertices(&[vertices[self.ai], vertices[self.bi], vertices[self.ci]])
+    }
+
     /**
-      * Intersect the face with a ray.
+      * Intersect the face with a ray using the Moller-Trumbore algorithm,
+      * which makes no assumption about the ray origin.  Barycentric
+      * coordinates are rejected as early as possible.
       */
-    pub fn intersect(&self, ray: Ray, material: &Material, mesh: &Mesh) -> Option<Intersection> {
+    pub fn intersect(&self, ray: Ray, material: MaterialId, mesh: &Mesh) -> Option<Intersection> {
+        self.hit(ray).map(|(t, beta, gamma)| self.intersection(t, beta, gamma, material, mesh))
+    }
+
+    /// Distance and barycentric coordinates beta and gamma of a hit
+    fn hit(&self, ray: Ray) -> Option<(f32, f32, f32)> {
         let d       = ray.direction;
-        let det_a   = self.ab_pdet_ac.dot(d);
-        let beta    = self.ar_pdet_ac.dot(d) / det_a;
-        let gamma   = self.ab_pdet_ar.dot(d) / det_a;
+        let p       = d.cross(self.ac);
+        let det     = self.ab.dot(p);
+        if det == 0.0 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let ar      = ray.origin - self.a;
+        let beta    = ar.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+
+        let q       = ar.cross(self.ab);
+        let gamma   = d.dot(q) * inv_det;
+        if gamma < 0.0 || beta + gamma > 1.0 {
+            return None;
+        }
+
+        let t       = self.ac.dot(q) * inv_det;
+        if t < 0.0 {
+            return None;
+        }
+        Some((t, beta, gamma))
+    }
+
+    /// Shade a hit, which is only worth doing once it is known to be needed
+    fn intersection(&self, t: f32, beta: f32, gamma: f32, material: MaterialId,
+                    mesh: &Mesh) -> Intersection {
         let alpha   = 1.0 - beta - gamma;
-        let t       = self.det_t / det_a;
 
         let norm_a  = mesh.vertex_normals[self.ai];
         let norm_b  = mesh.vertex_normals[self.bi];
         let norm_c  = mesh.vertex_normals[self.ci];
 
-        if beta >= 0.0 && gamma >= 0.0 && (beta + gamma) <= 1.0 && t >= 0.0 {
-            let normal = match mesh.shading {
-                Shading::Flat => {
-                    (norm_a + norm_b + norm_c).norm()
-                },
-                Shading::Smooth =>
-                    ( norm_a.scale(alpha)
-                    + norm_b.scale(beta)
-                    + norm_c.scale(gamma))
-                    .norm()
-            };
-            Some(Intersection::new(t, normal, material))
-        } else {
-            None
-        }
+        let normal = match mesh.shading {
+            Shading::Flat => {
+                (norm_a + norm_b + norm_c).norm()
+            },
+            Shading::Smooth =>
+                ( norm_a.scale(alpha)
+                + norm_b.scale(beta)
+                + norm_c.scale(gamma))
+                .norm()
+        };
+        Intersection::new(t, normal, material)
     }
 
     /**
-      * Precompute partial determinants for intersection.
+      * Precompute the first vertex and edge vectors for intersection.
       */
-    pub fn prepare(&self, origin: &Vec3f, vertices: &Vec<Vec3f>) -> Face {
+    pub fn prepare(&self, vertices: &[Vec3f]) -> Face {
         let a = vertices[self.ai];
         let b = vertices[self.bi];
         let c = vertices[self.ci];
-        let a_b = a - b;
-        let a_c = a - c;
-        let a_r = a - *origin;
-        let ab_pdet_ac = a_b.partial_determinant(a_c);
         Face {
-            ab_pdet_ac: ab_pdet_ac,
-            ar_pdet_ac: a_r.partial_determinant(a_c),
-            ab_pdet_ar: a_b.partial_determinant(a_r),
-            det_t: ab_pdet_ac.dot(a_r),
+            a,
+            ab: b - a,
+            ac: c - a,
             .. self.clone()
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Hash)]
 pub enum Shading {
     Smooth,
     Flat,
 }
 
+/**
+ * Triangle mesh.  Vertex data is shared so that copies of a mesh, such as
+ * the one its BVH is built for, do not duplicate it.
+ */
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub struct Mesh {
     pub faces: Vec<Face>,
-    pub vertices: Vec<Vec3f>,
-    pub vertex_normals: Vec<Vec3f>,
+    pub vertices: Arc<Vec<Vec3f>>,
+    pub vertex_normals: Arc<Vec<Vec3f>>,
     pub shading: Shading,
+    #[serde(skip_deserializing)]
+    pub bvh: Bvh,
 }
 
 impl Mesh {
@@ -127,15 +160,17 @@ impl Mesh {
         let lines   : Vec<String> = input.lines().map(|l| l.unwrap()).collect();
         let vertices: Vec<Vec3f> = lines.iter()
             .filter(|l| l.starts_with("v "))
-            .map(Mesh::read_vertex)
+            .map(|l| Mesh::read_vertex(l))
             .collect();
         let faces   : Vec<Face> = lines.iter()
             .filter(|l| l.starts_with("f "))
             .map(|l| Face::read(l))
             .collect();
+        // Faces with no area have no normal, and are left out
         let vertex_normals = faces.iter()
-            .fold(vec![Vec3f::zero(); vertices.len()], |mut vn, f| {
-                let n = f.normal(&vertices);
+            .map(|f| (f, f.normal(&vertices)))
+            .filter(|&(_, n)| n.x.is_finite() && n.y.is_finite() && n.z.is_finite())
+            .fold(vec![Vec3f::zero(); vertices.len()], |mut vn, (f, n)| {
                 vn[f.ai] = vn[f.ai] + n;
                 vn[f.bi] = vn[f.bi] + n;
                 vn[f.ci] = vn[f.ci] + n;
@@ -143,73 +178,102 @@ impl Mesh {
         });
         let vertex_normals = vertex_normals.iter().map(|v| v.norm()).collect();
         Mesh {
-            faces: faces,
-            vertices: vertices,
-            vertex_normals: vertex_normals,
-            shading: shading,
+            faces,
+            vertices: Arc::new(vertices),
+            vertex_normals: Arc::new(vertex_normals),
+            shading,
+            bvh: Bvh::default(),
         }
     }
 
-    fn read_vertex(s: &String) -> Vec3f {
+    fn read_vertex(s: &str) -> Vec3f {
         let v: Vec<f32> = s.split_whitespace()
             .filter_map(|x| f32::from_str(x).ok())
             .collect();
         Vec3f { x: v[0], y: v[1], z: v[2] }
     }
 
-    pub fn transform(&self, t: &Mat4f, origin: &Vec3f) -> Self {
-        let vertices = self.vertices.iter()
-            .map(|&v| t.transform_point(v))
-            .collect();
-        let faces = self.faces.iter()
-            .map(|f| f.prepare(origin, &vertices))
-            .collect();
+    /**
+      * Precompute face data for intersection.  Meshes stay in object space
+      * and are placed in the scene by instancing.
+      */
+    pub fn prepare(&self) -> Self {
         Mesh {
-            faces: faces,
-            vertices: vertices,
-            vertex_normals: self.vertex_normals.iter()
-                .map(|&v| t.transform_direction(v))
-                .collect(),
-            shading: self.shading,
+            faces: self.faces.iter().map(|f| f.prepare(&self.vertices)).collect(),
+            .. self.clone()
         }
     }
 
-    pub fn intersect(&self, ray: Ray, material: &Material) -> Vec<Intersection> {
-        self.faces.iter()
-            .filter_map(|f| f.intersect(ray, material, self))
-            .collect()
+    /// Vertices of each face, in object space
+    pub fn triangles(&self) -> impl Iterator<Item = [Vec3f; 3]> + '_ {
+        let v = &self.vertices;
+        self.faces.iter().map(move |f| [v[f.ai], v[f.bi], v[f.ci]])
     }
 
-    pub fn dissect(&self, face_limit: u32) -> ObjectTree {
-        let idxs: HashSet<usize> = self.faces.iter()
-            .flat_map(|f| vec![f.ai, f.bi, f.ci])
-            .collect();
-        let vertices: Vec<Vec3f> = idxs.iter().map(|&i| self.vertices[i]).collect();
-        let bbox = BoundingBox::from_vertices(&vertices);
+    pub fn bounding_box(&self) -> BoundingBox {
+        self.faces.iter().fold(BoundingBox::empty(), |b, f| {
+            b.union(&f.bounding_box(&self.vertices))
+        })
+    }
 
-        if self.faces.len() <= face_limit as usize {
-            ObjectTree::BoundingBox {
-                child: Box::new(ObjectTree::Mesh(self.clone())),
-                bbox: bbox,
-            }
-        } else {
-            let dimension = match bbox.max - bbox.min {
-                v if v.x > v.y && v.x > v.z => Vec3f::get_x,
-                v if v.y > v.x && v.y > v.z => Vec3f::get_y,
-                _ => Vec3f::get_z,
-            };
-            let face_dim = |f: &Face| dimension(&self.vertices[f.ai]);
-            let mut sorted_faces = self.faces.clone();
-            sorted_faces.sort_by(|a, b| {
-                face_dim(a).partial_cmp(&face_dim(b)).unwrap_or(Equal)
-            });
-            let (f1, f2) = sorted_faces.split_at(sorted_faces.len() / 2);
-            let m1 = Mesh {faces: f1.to_vec(), .. self.clone()}.dissect(face_limit);
-            let m2 = Mesh {faces: f2.to_vec(), .. self.clone()}.dissect(face_limit);
-            ObjectTree::BoundingBox {
-                child: Box::new(ObjectTree::Group(vec![m1, m2])),
-                bbox: bbox,
+    /// All intersections with the ray, in no particular order
+    pub fn intersect(&self, ray: Ray, material: MaterialId) -> Vec<Intersection> {
+        debug_assert!(self.is_built(), "Mesh intersected before build_bvh");
+        let mut intersections = vec![];
+        self.bvh.traverse(ray, f32::INFINITY, |i, t_max| {
+            intersections.extend(self.faces[i].intersect(ray, material, self));
+            t_max
+        });
+        intersections
+    }
+
+    /// Nearest intersection with the ray closer than t_max
+    pub fn nearest(&self, ray: Ray, t_max: f32, material: MaterialId) -> Option<Intersection> {
+        debug_assert!(self.is_built(), "Mesh intersected before build_bvh");
+        let mut nearest = None;
+        self.bvh.traverse(ray, t_max, |i, t_max| {
+            match self.faces[i].hit(ray) {
+                Some((t, beta, gamma)) if t < t_max => {
+                    nearest = Some((i, t, beta, gamma));
+                    t
+                },
+                _ => t_max,
             }
-        }
+        });
+        nearest.map(|(i, t, beta, gamma)| {
+            self.faces[i].intersection(t, beta, gamma, material, self)
+        })
+    }
+
+    /// Determine if any face is hit closer than t_max
+    pub fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        debug_assert!(self.is_built(), "Mesh intersected before build_bvh");
+        self.bvh.any(ray, t_max, |i| self.faces[i].hit(ray).is_some_and(|(t, _, _)| t < t_max))
+    }
+
+    /// Whether the faces can be intersected, which needs build_bvh
+    fn is_built(&self) -> bool {
+        self.faces.is_empty() || !self.bvh.is_empty()
+    }
+
+    /**
+      * Build a surface area heuristic BVH over the faces, with at most
+      * face_limit faces in each leaf, and summarize its quality.  The faces
+      * are reordered so that each leaf covers a contiguous range.
+      */
+    pub fn build_bvh(&self, face_limit: u32) -> (Mesh, BvhStats) {
+        let bounds: Vec<BoundingBox> = self.faces.iter()
+            .map(|f| f.bounding_box(&self.vertices))
+            .collect();
+        let tree = BvhTree::build(&bounds, face_limit as usize);
+        let (bvh, order) = Bvh::new(&tree);
+        let mesh = Mesh {
+            faces: order.iter().map(|&i| self.faces[i].clone()).collect(),
+            vertices: self.vertices.clone(),
+            vertex_normals: self.vertex_normals.clone(),
+            shading: self.shading,
+            bvh,
+        };
+        (mesh, tree.stats())
     }
 }
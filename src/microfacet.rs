@@ -0,0 +1,215 @@
+//! Physically based metal/roughness materials with a GGX microfacet BRDF
+
+use std::f32::consts::PI;
+use color::Color;
+use math::Vec3f;
+use sampler::Rng;
+
+/**
+ * Material in the metal/roughness convention of common authoring tools.
+ * Metals reflect in their base color and have no diffuse light, while
+ * other materials are diffuse in their base color under a colorless
+ * specular layer reflecting 4% of light head on.  Specular reflection
+ * follows the GGX (Trowbridge-Reitz) distribution of microfacet normals
+ * with Smith shadowing and Schlick's Fresnel approximation.  Roughness is
+ * squared to give the width of the distribution, as those tools do.
+ */
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub struct Pbr {
+    pub base_color: Color,
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+}
+
+fn default_roughness() -> f32 {
+    0.5
+}
+
+/// Narrowest distribution, as perfectly smooth highlights cannot be sampled
+const MIN_ALPHA: f32 = 1e-3;
+
+impl Pbr {
+    fn alpha(&self) -> f32 {
+        (self.roughness * self.roughness).max(MIN_ALPHA)
+    }
+
+    /// Specular reflectance head on
+    fn f0(&self) -> Vec3f {
+        let dielectric = Vec3f::new(0.04, 0.04, 0.04);
+        dielectric.scale(1.0 - self.metallic) + self.base_color.vec3f().scale(self.metallic)
+    }
+
+    /// Chance of sampling the specular lobe rather than the diffuse one
+    fn specular_probability(&self) -> f32 {
+        0.5 + 0.5 * self.metallic
+    }
+
+    /**
+     * BRDF times the cosine of the angle of incidence, for light arriving
+     * from direction light and leaving toward view.  Both directions point
+     * away from the surface.
+     */
+    pub fn evaluate(&self, normal: Vec3f, view: Vec3f, light: Vec3f) -> Vec3f {
+        let (n_v, n_l) = (normal.dot(view), normal.dot(light));
+        if n_v <= 0.0 || n_l <= 0.0 {
+            return Vec3f::zero();
+        }
+        let half = (view + light).norm();
+        let alpha = self.alpha();
+        let fresnel = schlick(self.f0(), view.dot(half));
+        let specular = fresnel.scale(ggx(normal.dot(half), alpha) * smith(n_v, n_l, alpha)
+                                     / (4.0 * n_v * n_l));
+        let diffuse = (Vec3f::new(1.0, 1.0, 1.0) - fresnel)
+            .point_mul(self.base_color.vec3f())
+            .scale((1.0 - self.metallic) / PI);
+        (diffuse + specular).scale(n_l)
+    }
+
+    /// Probability density of sample choosing the direction light
+    pub fn pdf(&self, normal: Vec3f, view: Vec3f, light: Vec3f) -> f32 {
+        let n_l = normal.dot(light);
+        if n_l <= 0.0 || normal.dot(view) <= 0.0 {
+            return 0.0;
+        }
+        let half = (view + light).norm();
+        let n_h = normal.dot(half);
+        let specular = ggx(n_h, self.alpha()) * n_h / (4.0 * view.dot(half).abs().max(1e-6));
+        let p = self.specular_probability();
+        p * specular + (1.0 - p) * n_l / PI
+    }
+
+    /**
+     * Choose a direction for light to arrive from, returning it with the
+     * BRDF times cosine over its density.  The specular lobe is sampled by
+     * drawing a microfacet normal from the GGX distribution, and the diffuse
+     * lobe by the cosine.  Returns None for directions below the surface.
+     */
+    pub fn sample(&self, normal: Vec3f, view: Vec3f, rng: &mut Rng) -> Option<(Vec3f, Vec3f)> {
+        let (u, v) = normal.orthonormal_basis();
+        let choice = rng.next_f32();
+        let (r1, phi) = (rng.next_f32(), 2.0 * PI * rng.next_f32());
+        let (cos, sin) = if choice < self.specular_probability() {
+            let a2 = self.alpha() * self.alpha();
+            let cos2 = (1.0 - r1) / (1.0 + (a2 - 1.0) * r1);
+            (cos2.sqrt(), (1.0 - cos2).max(0.0).sqrt())
+        } else {
+            ((1.0 - r1).sqrt(), r1.sqrt())
+        };
+        let direction = u.scale(sin * phi.cos()) + v.scale(sin * phi.sin()) + normal.scale(cos);
+        let light = if choice < self.specular_probability() {
+            (direction.scale(2.0 * view.dot(direction)) - view).norm()
+        } else {
+            direction.norm()
+        };
+        let pdf = self.pdf(normal, view, light);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((light, self.evaluate(normal, view, light).scale(1.0 / pdf)))
+    }
+
+    /**
+     * Share of light a Whitted tracer should mirror toward view, which is
+     * the Fresnel reflectance faded out as the distribution widens.
+     */
+    pub fn mirror(&self, normal: Vec3f, view: Vec3f) -> Vec3f {
+        schlick(self.f0(), normal.dot(view).max(0.0)).scale((1.0 - self.alpha()).max(0.0))
+    }
+}
+
+/// GGX density of microfacet normals at cosine n_h to the surface normal
+fn ggx(n_h: f32, alpha: f32) -> f32 {
+    if n_h <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let d = n_h * n_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+/// Fraction of microfacets both lit and visible, by Smith's separable form
+fn smith(n_v: f32, n_l: f32, alpha: f32) -> f32 {
+    let g1 = |cos: f32| {
+        let a2 = alpha * alpha;
+        2.0 * cos / (cos + (a2 + (1.0 - a2) * cos * cos).sqrt())
+    };
+    g1(n_v) * g1(n_l)
+}
+
+/// Schlick's approximation of Fresnel reflectance with reflectance f0 head on
+fn schlick(f0: Vec3f, cos: f32) -> Vec3f {
+    let one = Vec3f::new(1.0, 1.0, 1.0);
+    f0 + (one - f0).scale((1.0 - cos.clamp(0.0, 1.0)).powi(5))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NORMAL: Vec3f = Vec3f { x: 0.0, y: 1.0, z: 0.0 };
+
+    fn material(metallic: f32, roughness: f32) -> Pbr {
+        Pbr { base_color: Color::White, metallic, roughness }
+    }
+
+    #[test]
+    fn test_ggx_normalized() {
+        // Projected microfacet area integrates to one over the hemisphere
+        for &alpha in &[0.1, 0.5, 1.0] {
+            let n = 200000;
+            let mut rng = Rng::new(1);
+            let total: f32 = (0..n).map(|_| {
+                // Uniform directions over the hemisphere have density 1 / 2pi
+                let cos = rng.next_f32();
+                ggx(cos, alpha) * cos * 2.0 * PI
+            }).sum();
+            let integral = total / n as f32;
+            assert!((integral - 1.0).abs() < 0.05, "{} {}", alpha, integral);
+        }
+    }
+
+    #[test]
+    fn test_energy_conserving() {
+        let view = Vec3f::new(0.6, 0.8, 0.0);
+        for &(metallic, roughness) in &[(0.0, 0.2), (0.0, 0.8), (1.0, 0.3), (1.0, 1.0)] {
+            let pbr = material(metallic, roughness);
+            let mut rng = Rng::new(2);
+            let n = 20000;
+            let total = (0..n)
+                .filter_map(|_| pbr.sample(NORMAL, view, &mut rng))
+                .fold(Vec3f::zero(), |a, (_, weight)| a + weight);
+            // Rough surfaces lose the light scattered more than once
+            let albedo = total.x / n as f32;
+            assert!(albedo <= 1.01, "{} {} {}", metallic, roughness, albedo);
+            assert!(albedo > if roughness < 0.5 { 0.95 } else { 0.3 });
+        }
+    }
+
+    #[test]
+    fn test_sampling_matches_brdf() {
+        // Sample weights and uniform sampling estimate the same integral
+        let pbr = Pbr { base_color: Color::Orange, metallic: 0.5, roughness: 0.5 };
+        let view = Vec3f::new(0.0, 0.6, 0.8);
+        let n = 200000;
+        let mut rng = Rng::new(3);
+        let sampled = (0..n)
+            .filter_map(|_| pbr.sample(NORMAL, view, &mut rng))
+            .fold(Vec3f::zero(), |a, (light, weight)| {
+                assert!(light.dot(NORMAL) > 0.0);
+                a + weight
+            })
+            .scale(1.0 / n as f32);
+        let uniform = (0..n)
+            .map(|_| {
+                let (cos, phi) = (rng.next_f32(), 2.0 * PI * rng.next_f32());
+                let sin = (1.0 - cos * cos).sqrt();
+                let light = Vec3f::new(sin * phi.cos(), cos, sin * phi.sin());
+                pbr.evaluate(NORMAL, view, light).scale(2.0 * PI)
+            })
+            .fold(Vec3f::zero(), |a, c| a + c)
+            .scale(1.0 / n as f32);
+        assert!((sampled - uniform).magnitude() < 0.02, "{:?} {:?}", sampled, uniform);
+    }
+}
@@ -1,68 +1,597 @@
-use math::{Vec3f, Mat4f};
+use std::cmp::Ordering::Equal;
+use std::f32::consts::PI;
+use math::{ArrayVec, Vec3f, Mat4f, solve_quadratic, solve_quartic};
 use ray_tracer::{Ray, Intersection};
-use scene::Material;
+use scene::MaterialId;
 use bounding_box::BoundingBox;
+use sampler::Rng;
 
+/**
+ * Analytic primitives.  Except for planes, each is defined in object space
+ * and keeps its object-to-world transform, so that any affine transform
+ * renders exactly.  Cylinders, cones and tori are aligned with the y axis.
+ */
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub enum Primitive {
-    Sphere { radius: f32, center: Vec3f },
+    /// A sphere, which becomes an ellipsoid under non-uniform scaling.
+    Sphere {
+        radius: f32,
+        center: Vec3f,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        transform: Mat4f,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        inverse: Mat4f,
+    },
+    /// An infinite plane, which maps to another plane under any transform.
+    Plane {
+        point: Vec3f,
+        normal: Vec3f,
+    },
+    /// A disk, which becomes an ellipse under non-uniform scaling.
+    Disk {
+        center: Vec3f,
+        normal: Vec3f,
+        radius: f32,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        transform: Mat4f,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        inverse: Mat4f,
+    },
+    /// An axis-aligned box in object space.
+    Box {
+        min: Vec3f,
+        max: Vec3f,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        transform: Mat4f,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        inverse: Mat4f,
+    },
+    /// A capped cylinder rising from the center of its base.
+    Cylinder {
+        base: Vec3f,
+        radius: f32,
+        height: f32,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        transform: Mat4f,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        inverse: Mat4f,
+    },
+    /// A capped cone rising from the center of its base to its apex.
+    Cone {
+        base: Vec3f,
+        radius: f32,
+        height: f32,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        transform: Mat4f,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        inverse: Mat4f,
+    },
+    /// A torus lying in the xz plane, swept by the minor radius around a
+    /// circle of the major radius.
+    Torus {
+        center: Vec3f,
+        major_radius: f32,
+        minor_radius: f32,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        transform: Mat4f,
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        inverse: Mat4f,
+    },
 }
 
 impl Primitive {
-    pub fn transform(&self, t: &Mat4f) -> Self {
-        match *self {
-            Primitive::Sphere { radius: r, center: c } => {
-                Primitive::Sphere {
-                    radius: r * t.r1.x,
-                    center: t.transform_point(c),
-                }
+    /// Apply transform t, failing if it is singular
+    pub fn transform(&self, t: &Mat4f) -> Result<Self, String> {
+        let mut p = self.clone();
+        match p {
+            Primitive::Plane { ref mut point, ref mut normal } => {
+                let normal_transform = t.inverse_transpose()
+                    .map_err(|e| format!("Unable to invert plane transform: {}", e))?;
+                *point = t.transform_point(*point);
+                *normal = normal_transform.transform_normal(*normal);
+            },
+            Primitive::Sphere { ref mut transform, ref mut inverse, .. } |
+            Primitive::Disk { ref mut transform, ref mut inverse, .. } |
+            Primitive::Box { ref mut transform, ref mut inverse, .. } |
+            Primitive::Cylinder { ref mut transform, ref mut inverse, .. } |
+            Primitive::Cone { ref mut transform, ref mut inverse, .. } |
+            Primitive::Torus { ref mut transform, ref mut inverse, .. } => {
+                *transform = t.mm_multiply(transform);
+                *inverse = transform.inverse()
+                    .map_err(|e| format!("Unable to invert primitive transform: {}", e))?;
             },
         }
+        Ok(p)
+    }
+
+    /// All intersections in front of the ray, nearest first
+    pub fn intersect(&self, ray: Ray, material: MaterialId) -> Vec<Intersection> {
+        let mut intersections: Vec<Intersection> = self.candidates(ray).into_iter()
+            .filter(|&(t, _)| t >= 0.0)
+            .map(|(t, n)| Intersection::new(t, self.world_normal(n), material))
+            .collect();
+        intersections.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Equal));
+        intersections
     }
 
-    pub fn intersect(&self, ray: Ray, material: &Material) -> Vec<Intersection> {
+    /// Nearest intersection in front of the ray closer than t_max
+    pub fn nearest(&self, ray: Ray, t_max: f32, material: MaterialId) -> Option<Intersection> {
+        self.candidates(ray).into_iter()
+            .filter(|&(t, _)| t >= 0.0 && t < t_max)
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Equal))
+            .map(|(t, n)| Intersection::new(t, self.world_normal(n), material))
+    }
+
+    /// Determine if the primitive is hit before t_max, without shading
+    pub fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        self.candidates(ray).iter().any(|&(t, _)| t >= 0.0 && t < t_max)
+    }
+
+    /**
+     * Choose a point on the surface uniformly by object space area, returning
+     * it with its normal and the density of choosing it by world space area.
+     * Only spheres and disks can be sampled, as emitters are.
+     */
+    pub fn sample_surface(&self, rng: &mut Rng) -> Option<(Vec3f, Vec3f, f32)> {
+        let (point, normal, area, transform, inverse) = match *self {
+            Primitive::Sphere { radius, center, ref transform, ref inverse } => {
+                let z = 1.0 - 2.0 * rng.next_f32();
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * PI * rng.next_f32();
+                let normal = Vec3f::new(r * phi.cos(), r * phi.sin(), z);
+                (center + normal.scale(radius), normal, 4.0 * PI * radius * radius,
+                 transform, inverse)
+            },
+            Primitive::Disk { center, normal, radius, ref transform, ref inverse } => {
+                let normal = normal.norm();
+                let (u, v) = normal.orthonormal_basis();
+                let (r, phi) = (radius * rng.next_f32().sqrt(), 2.0 * PI * rng.next_f32());
+                let point = center + u.scale(r * phi.cos()) + v.scale(r * phi.sin());
+                (point, normal, PI * radius * radius, transform, inverse)
+            },
+            _ => return None,
+        };
+        // Area is scaled by the determinant, over the normal's scaling
+        let normal_transform = inverse.transpose();
+        let scaled_normal = normal_transform.transform_direction(normal);
+        let (x, y, z) = (transform.transform_direction(Vec3f::new(1.0, 0.0, 0.0)),
+                         transform.transform_direction(Vec3f::new(0.0, 1.0, 0.0)),
+                         transform.transform_direction(Vec3f::new(0.0, 0.0, 1.0)));
+        let scale = x.dot(y.cross(z)).abs() * scaled_normal.magnitude();
+        Some((transform.transform_point(point), scaled_normal.norm(), 1.0 / (area * scale)))
+    }
+
+    /// Whether sample_surface can sample the primitive
+    pub fn can_sample(&self) -> bool {
+        matches!(*self, Primitive::Sphere { .. } | Primitive::Disk { .. })
+    }
+
+    /// World to object space transform, which planes do not have
+    fn inverse(&self) -> Option<&Mat4f> {
         match *self {
-            Primitive::Sphere { radius, center } =>
-                Primitive::intersect_sphere(radius, center, ray, material),
+            Primitive::Plane { .. } => None,
+            Primitive::Sphere { ref inverse, .. } |
+            Primitive::Disk { ref inverse, .. } |
+            Primitive::Box { ref inverse, .. } |
+            Primitive::Cylinder { ref inverse, .. } |
+            Primitive::Cone { ref inverse, .. } |
+            Primitive::Torus { ref inverse, .. } => Some(inverse),
         }
     }
 
-    fn intersect_sphere(radius: f32, center: Vec3f, ray: Ray, material: &Material) -> Vec<Intersection> {
-        let o_c = ray.origin - center;
-        let b = 2.0 * ray.direction.dot(o_c);
-        let c = o_c.magnitude_squared() - radius.powi(2);
-        let discrim = b.powi(2) - 4.0 * c;
-        if discrim < 0.0 {
-            return vec![];
+    /// Unit world space normal from an unnormalized object space normal
+    fn world_normal(&self, n: Vec3f) -> Vec3f {
+        match self.inverse() {
+            Some(inverse) => inverse.transpose().transform_normal(n),
+            None => n.norm(),
         }
+    }
 
-        let t0 = (-b - discrim.sqrt()) / 2.0;
-        let t1 = (-b + discrim.sqrt()) / 2.0;
+    /**
+     * Candidate hit distances with unnormalized object space normals.  The
+     * ray is transformed to object space without normalizing its direction,
+     * so that distances match the world space ray.
+     */
+    fn candidates(&self, ray: Ray) -> ArrayVec<(f32, Vec3f)> {
+        let r = self.inverse().map_or(ray, |inverse| ray.transform(inverse));
+        match *self {
+            Primitive::Sphere { radius, center, .. } => intersect_sphere(radius, center, r),
+            Primitive::Plane { point, normal } => {
+                intersect_plane(point, normal, r).into_iter().collect()
+            },
+            Primitive::Disk { center, normal, radius, .. } => {
+                intersect_plane(center, normal, r).into_iter()
+                    .filter(|&(t, _)| {
+                        let p = r.origin + r.direction.scale(t);
+                        (p - center).magnitude_squared() <= radius.powi(2)
+                    })
+                    .collect()
+            },
+            Primitive::Box { min, max, .. } => intersect_box(min, max, r),
+            Primitive::Cylinder { base, radius, height, .. } => {
+                intersect_cylinder(base, radius, height, r)
+            },
+            Primitive::Cone { base, radius, height, .. } => intersect_cone(base, radius, height, r),
+            Primitive::Torus { center, major_radius, minor_radius, .. } => {
+                intersect_torus(center, major_radius, minor_radius, r)
+            },
+        }
+    }
 
-        let normal = |t: f32| (ray.direction.scale(t) + o_c).scale(1.0 / radius);
+    /**
+     * Bound the primitive after transformation.  Each bound is tight, built
+     * from the extents of transformed disks and spheres, which are the
+     * transformed radius vectors projected onto each world axis.
+     */
+    pub fn bounding_box(&self) -> BoundingBox {
+        let y_axis = Vec3f::new(0.0, 1.0, 0.0);
+        match *self {
+            Primitive::Sphere { radius, center, ref transform, .. } => {
+                centered_box(transform.transform_point(center), ellipsoid_extent(transform, radius))
+            },
+            Primitive::Plane { .. } => BoundingBox::infinite(),
+            Primitive::Disk { center, normal, radius, ref transform, .. } => {
+                centered_box(transform.transform_point(center),
+                             ellipse_extent(transform, normal, radius))
+            },
+            Primitive::Box { min, max, ref transform, .. } => {
+                BoundingBox { min, max }.transform(transform)
+            },
+            Primitive::Cylinder { base, radius, height, ref transform, .. } => {
+                let extent = ellipse_extent(transform, y_axis, radius);
+                let top = base + y_axis.scale(height);
+                centered_box(transform.transform_point(base), extent)
+                    .union(&centered_box(transform.transform_point(top), extent))
+            },
+            Primitive::Cone { base, radius, height, ref transform, .. } => {
+                let apex = transform.transform_point(base + y_axis.scale(height));
+                let extent = ellipse_extent(transform, y_axis, radius);
+                centered_box(transform.transform_point(base), extent)
+                    .union(&BoundingBox { min: apex, max: apex })
+            },
+            Primitive::Torus { center, major_radius, minor_radius, ref transform, .. } => {
+                let extent = ellipse_extent(transform, y_axis, major_radius)
+                    + ellipsoid_extent(transform, minor_radius);
+                centered_box(transform.transform_point(center), extent)
+            },
+        }
+    }
+
+}
+
+fn intersect_sphere(radius: f32, center: Vec3f, ray: Ray) -> ArrayVec<(f32, Vec3f)> {
+    let o_c = ray.origin - center;
+    let a = ray.direction.magnitude_squared();
+    let b = 2.0 * ray.direction.dot(o_c);
+    let c = o_c.magnitude_squared() - radius.powi(2);
+    let discrim = b.powi(2) - 4.0 * a * c;
+    if discrim < 0.0 {
+        return ArrayVec::new();
+    }
+
+    let t0 = (-b - discrim.sqrt()) / (2.0 * a);
+    let t1 = (-b + discrim.sqrt()) / (2.0 * a);
+    let normal = |t: f32| ray.direction.scale(t) + o_c;
+    ArrayVec::from([(t0, normal(t0)), (t1, normal(t1))])
+}
+
+fn intersect_plane(point: Vec3f, normal: Vec3f, ray: Ray) -> Option<(f32, Vec3f)> {
+    let denom = ray.direction.dot(normal);
+    if denom == 0.0 {
+        return None;
+    }
+    Some(((point - ray.origin).dot(normal) / denom, normal))
+}
 
-        if t0 < 0.0 {
-            if t1 < 0.0 {
-                vec![]
-            } else {
-                vec![ Intersection::new(t1, normal(t1), material) ]
+/**
+ * Intersect a box with the slab method, remembering which axis gives the
+ * entry and exit distances in order to find the face normals.
+ */
+fn intersect_box(min: Vec3f, max: Vec3f, ray: Ray) -> ArrayVec<(f32, Vec3f)> {
+    let axes = [
+        (ray.origin.x, ray.direction.x, min.x, max.x, Vec3f::new(1.0, 0.0, 0.0)),
+        (ray.origin.y, ray.direction.y, min.y, max.y, Vec3f::new(0.0, 1.0, 0.0)),
+        (ray.origin.z, ray.direction.z, min.z, max.z, Vec3f::new(0.0, 0.0, 1.0)),
+    ];
+    let mut near = (f32::MIN, Vec3f::zero());
+    let mut far = (f32::MAX, Vec3f::zero());
+    for &(o, d, lo, hi, axis) in axes.iter() {
+        if d == 0.0 {
+            if o < lo || o > hi {
+                return ArrayVec::new();
             }
+            continue;
+        }
+        let (t_lo, t_hi) = ((lo - o) / d, (hi - o) / d);
+        let (t1, n1, t2, n2) = if t_lo < t_hi {
+            (t_lo, axis.scale(-1.0), t_hi, axis)
         } else {
-            vec![Intersection::new(t0, normal(t0), material),
-                 Intersection::new(t1, normal(t1), material)]
+            (t_hi, axis, t_lo, axis.scale(-1.0))
+        };
+        if t1 > near.0 {
+            near = (t1, n1);
+        }
+        if t2 < far.0 {
+            far = (t2, n2);
         }
     }
+    if near.0 > far.0 {
+        ArrayVec::new()
+    } else {
+        ArrayVec::from([near, far])
+    }
+}
 
-    pub fn bounding_box(&self) -> BoundingBox {
-        match *self {
-            Primitive::Sphere { radius, center } => {
-                let r = Vec3f::new(radius, radius, radius);
-                BoundingBox {
-                    min: center - r,
-                    max: center + r,
-                }
-            }
+/// Intersect the cap of a cylinder or cone, a disk perpendicular to the y axis
+fn intersect_cap(center: Vec3f, radius: f32, normal_y: f32, ray: Ray) -> Option<(f32, Vec3f)> {
+    if ray.direction.y == 0.0 {
+        return None;
+    }
+    let t = (center.y - ray.origin.y) / ray.direction.y;
+    let p = ray.origin + ray.direction.scale(t) - center;
+    if p.x.powi(2) + p.z.powi(2) <= radius.powi(2) {
+        Some((t, Vec3f::new(0.0, normal_y, 0.0)))
+    } else {
+        None
+    }
+}
+
+fn intersect_cylinder(base: Vec3f, radius: f32, height: f32, ray: Ray) -> ArrayVec<(f32, Vec3f)> {
+    let (o, d) = (ray.origin - base, ray.direction);
+    let a = d.x.powi(2) + d.z.powi(2);
+    let b = 2.0 * (o.x * d.x + o.z * d.z);
+    let c = o.x.powi(2) + o.z.powi(2) - radius.powi(2);
+    let mut candidates: ArrayVec<(f32, Vec3f)> = solve_quadratic(a as f64, b as f64, c as f64)
+        .into_iter()
+        .map(|t| t as f32)
+        .filter(|&t| (0.0..=height).contains(&(o.y + d.y * t)))
+        .map(|t| (t, Vec3f::new(o.x + d.x * t, 0.0, o.z + d.z * t)))
+        .collect();
+    let top = base + Vec3f::new(0.0, height, 0.0);
+    candidates.extend(intersect_cap(base, radius, -1.0, ray));
+    candidates.extend(intersect_cap(top, radius, 1.0, ray));
+    candidates
+}
+
+/**
+ * Intersect a cone, whose radius at height y above the base is
+ * k * (height - y) with slope k = radius / height.
+ */
+fn intersect_cone(base: Vec3f, radius: f32, height: f32, ray: Ray) -> ArrayVec<(f32, Vec3f)> {
+    let (o, d) = (ray.origin - base, ray.direction);
+    let k2 = (radius / height).powi(2);
+    let h = height - o.y;
+    let a = d.x.powi(2) + d.z.powi(2) - k2 * d.y.powi(2);
+    let b = 2.0 * (o.x * d.x + o.z * d.z + k2 * h * d.y);
+    let c = o.x.powi(2) + o.z.powi(2) - k2 * h.powi(2);
+    let mut candidates: ArrayVec<(f32, Vec3f)> = solve_quadratic(a as f64, b as f64, c as f64)
+        .into_iter()
+        .map(|t| t as f32)
+        .filter(|&t| (0.0..=height).contains(&(o.y + d.y * t)))
+        .map(|t| {
+            let p = o + d.scale(t);
+            (t, Vec3f::new(p.x, k2 * (height - p.y), p.z))
+        })
+        .collect();
+    candidates.extend(intersect_cap(base, radius, -1.0, ray));
+    candidates
+}
+
+/**
+ * Intersect a torus by solving the quartic
+ * (|p|^2 + R^2 - r^2)^2 = 4 R^2 (p.x^2 + p.z^2) along the ray.  To keep the
+ * solver well conditioned the direction is normalized and the ray is started
+ * where it enters the bounding sphere.
+ */
+fn intersect_torus(center: Vec3f, major: f32, minor: f32, ray: Ray) -> ArrayVec<(f32, Vec3f)> {
+    let scale = ray.direction.magnitude();
+    let d = ray.direction.scale(1.0 / scale);
+    let o = ray.origin - center;
+
+    let bound = major + minor;
+    let b = o.dot(d);
+    let discrim = b.powi(2) - (o.magnitude_squared() - bound.powi(2));
+    if discrim < 0.0 || -b + discrim.sqrt() < 0.0 {
+        return ArrayVec::new();
+    }
+    let t_start = (-b - discrim.sqrt()).max(0.0);
+    let o = o + d.scale(t_start);
+
+    let (ox, oy, oz) = (o.x as f64, o.y as f64, o.z as f64);
+    let (dx, dy, dz) = (d.x as f64, d.y as f64, d.z as f64);
+    let (r2, rr2) = ((major as f64).powi(2), (minor as f64).powi(2));
+    let od = ox * dx + oy * dy + oz * dz;
+    let e = ox * ox + oy * oy + oz * oz + r2 - rr2;
+    let c4 = 1.0;
+    let c3 = 4.0 * od;
+    let c2 = 4.0 * od * od + 2.0 * e - 4.0 * r2 * (dx * dx + dz * dz);
+    let c1 = 4.0 * od * e - 8.0 * r2 * (ox * dx + oz * dz);
+    let c0 = e * e - 4.0 * r2 * (ox * ox + oz * oz);
+
+    solve_quartic(c4, c3, c2, c1, c0).into_iter()
+        .map(|t| t as f32)
+        .filter(|&t| t >= 0.0)
+        .map(|t| {
+            let p = o + d.scale(t);
+            let s = p.magnitude_squared() + major.powi(2) - minor.powi(2);
+            let normal = p.scale(s) - Vec3f::new(p.x, 0.0, p.z).scale(2.0 * major.powi(2));
+            ((t + t_start) / scale, normal)
+        })
+        .collect()
+}
+
+/// Half extents along each world axis of a transformed sphere
+fn ellipsoid_extent(t: &Mat4f, radius: f32) -> Vec3f {
+    let extent = |row: Vec3f| radius * row.magnitude();
+    Vec3f::new(extent(Vec3f::new(t.r1.x, t.r1.y, t.r1.z)),
+               extent(Vec3f::new(t.r2.x, t.r2.y, t.r2.z)),
+               extent(Vec3f::new(t.r3.x, t.r3.y, t.r3.z)))
+}
+
+/// Half extents along each world axis of a transformed disk
+fn ellipse_extent(t: &Mat4f, normal: Vec3f, radius: f32) -> Vec3f {
+    let (u, v) = normal.norm().orthonormal_basis();
+    let (u, v) = (t.transform_direction(u), t.transform_direction(v));
+    let extent = |a: f32, b: f32| radius * (a.powi(2) + b.powi(2)).sqrt();
+    Vec3f::new(extent(u.x, v.x), extent(u.y, v.y), extent(u.z, v.z))
+}
+
+fn centered_box(center: Vec3f, extent: Vec3f) -> BoundingBox {
+    BoundingBox {
+        min: center - extent,
+        max: center + extent,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const X: Vec3f = Vec3f { x: 1.0, y: 0.0, z: 0.0 };
+    const Y: Vec3f = Vec3f { x: 0.0, y: 1.0, z: 0.0 };
+
+    fn placed(p: Primitive, t: Mat4f) -> Primitive {
+        p.transform(&t).expect("Unable to transform primitive")
+    }
+
+    fn unit_box() -> Primitive {
+        Primitive::Box {
+            min: Vec3f::zero(),
+            max: Vec3f::new(1.0, 1.0, 1.0),
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
         }
     }
 
+    fn cylinder() -> Primitive {
+        Primitive::Cylinder {
+            base: Vec3f::zero(),
+            radius: 1.0,
+            height: 2.0,
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
+        }
+    }
+
+    fn cone() -> Primitive {
+        Primitive::Cone {
+            base: Vec3f::zero(),
+            radius: 1.0,
+            height: 1.0,
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
+        }
+    }
+
+    fn torus() -> Primitive {
+        Primitive::Torus {
+            center: Vec3f::zero(),
+            major_radius: 2.0,
+            minor_radius: 0.5,
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
+        }
+    }
+
+    /// Check the distances and normals of every hit along a ray
+    fn assert_hits(p: &Primitive, origin: Vec3f, direction: Vec3f, expected: &[(f32, Vec3f)]) {
+        let ray = Ray { origin, direction };
+        let hits = p.intersect(ray, 0);
+        assert_eq!(hits.len(), expected.len(), "{:?}", hits);
+        for (hit, &(t, n)) in hits.iter().zip(expected) {
+            assert!((hit.distance - t).abs() < 1e-4, "{:?} is not at {}", hit, t);
+            assert!((hit.normal - n.norm()).magnitude() < 1e-4, "{:?} is not along {:?}", hit, n);
+        }
+        assert_eq!(p.nearest(ray, f32::INFINITY, 0), hits.first().copied());
+        assert_eq!(p.occluded(ray, f32::INFINITY), !expected.is_empty());
+    }
+
+    fn assert_box(p: &Primitive, min: Vec3f, max: Vec3f) {
+        let b = p.bounding_box();
+        assert!((b.min - min).magnitude() < 1e-5 && (b.max - max).magnitude() < 1e-5, "{:?}", b);
+    }
+
+    #[test]
+    fn test_box() {
+        let (x, y) = (X.scale(-1.0), Y.scale(-1.0));
+        assert_hits(&unit_box(), Vec3f::new(-5.0, 0.5, 0.5), X, &[(5.0, x), (6.0, X)]);
+        assert_hits(&unit_box(), Vec3f::new(0.5, 5.0, 0.5), y, &[(4.0, Y), (5.0, y)]);
+        assert_hits(&unit_box(), Vec3f::new(-5.0, 2.0, 0.5), X, &[]);
+        assert_hits(&unit_box(), Vec3f::new(0.5, 0.5, 0.5), X, &[(0.5, X)]);
+        assert_hits(&unit_box(), Vec3f::new(5.0, 0.5, 0.5), X, &[]);
+
+        // Rotated a quarter turn about y and doubled, faces keep their normals
+        let t = Mat4f::rotate(Y, 90.0).mm_multiply(&Mat4f::scale(Vec3f::new(2.0, 2.0, 2.0)));
+        let turned = placed(unit_box(), t);
+        assert_hits(&turned, Vec3f::new(-5.0, 1.0, -1.0), X, &[(5.0, x), (7.0, X)]);
+        assert_box(&turned, Vec3f::new(0.0, 0.0, -2.0), Vec3f::new(2.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn test_cylinder() {
+        let (x, y) = (X.scale(-1.0), Y.scale(-1.0));
+        assert_hits(&cylinder(), Vec3f::new(-5.0, 1.0, 0.0), X, &[(4.0, x), (6.0, X)]);
+        assert_hits(&cylinder(), Vec3f::new(0.5, 5.0, 0.0), y, &[(3.0, Y), (5.0, y)]);
+        assert_hits(&cylinder(), Vec3f::new(-5.0, 3.0, 0.0), X, &[]);
+        assert_hits(&cylinder(), Vec3f::new(1.5, 5.0, 0.0), y, &[]);
+        assert_hits(&cylinder(), Vec3f::new(0.0, 1.0, 0.0), X, &[(1.0, X)]);
+        // Entering the side and leaving through the top
+        let up = Vec3f::new(1.0, 1.0, 0.0);
+        assert_hits(&cylinder(), Vec3f::new(-2.0, 0.0, 0.0), up, &[(1.0, x), (2.0, Y)]);
+
+        // Squashed into an elliptic cylinder and raised
+        let t = Mat4f::translate(Vec3f::new(0.0, 1.0, 0.0))
+            .mm_multiply(&Mat4f::scale(Vec3f::new(2.0, 1.0, 0.5)));
+        let squashed = placed(cylinder(), t);
+        assert_hits(&squashed, Vec3f::new(-5.0, 2.0, 0.0), X, &[(3.0, x), (7.0, X)]);
+        assert_hits(&squashed, Vec3f::new(0.0, 2.0, -5.0), Vec3f::new(0.0, 0.0, 1.0),
+                    &[(4.5, Vec3f::new(0.0, 0.0, -1.0)), (5.5, Vec3f::new(0.0, 0.0, 1.0))]);
+        assert_box(&squashed, Vec3f::new(-2.0, 1.0, -0.5), Vec3f::new(2.0, 3.0, 0.5));
+    }
+
+    #[test]
+    fn test_cone() {
+        let (x, y) = (X.scale(-1.0), Y.scale(-1.0));
+        // The side slopes at 45 degrees, leaning its normals up
+        let side = |sign: f32| Vec3f::new(sign, 1.0, 0.0);
+        assert_hits(&cone(), Vec3f::new(-5.0, 0.5, 0.0), X, &[(4.5, side(-1.0)), (5.5, side(1.0))]);
+        assert_hits(&cone(), Vec3f::new(0.25, 5.0, 0.0), y, &[(4.25, side(1.0)), (5.0, y)]);
+        assert_hits(&cone(), Vec3f::new(-5.0, 1.5, 0.0), X, &[]);
+        assert_hits(&cone(), Vec3f::new(0.75, 5.0, 0.0), y, &[(4.75, side(1.0)), (5.0, y)]);
+        assert_hits(&cone(), Vec3f::new(-5.0, -0.5, 0.0), X, &[]);
+        assert_hits(&cone(), Vec3f::new(0.0, 0.5, 0.0), X, &[(0.5, side(1.0))]);
+        assert_hits(&cone(), Vec3f::new(0.0, 0.5, 0.0), y, &[(0.5, y)]);
+        assert_hits(&cone(), Vec3f::new(-5.0, 0.5, 0.0), x, &[]);
+
+        // Upside down, tall and shifted
+        let t = Mat4f::translate(Vec3f::new(1.0, 0.0, 0.0))
+            .mm_multiply(&Mat4f::scale(Vec3f::new(1.0, -3.0, 1.0)));
+        let flipped = placed(cone(), t);
+        assert_hits(&flipped, Vec3f::new(1.5, 5.0, 0.0), y,
+                    &[(5.0, Y), (6.5, Vec3f::new(3.0, -1.0, 0.0))]);
+        assert_box(&flipped, Vec3f::new(0.0, -3.0, -1.0), Vec3f::new(2.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_torus() {
+        let (x, y) = (X.scale(-1.0), Y.scale(-1.0));
+        assert_hits(&torus(), Vec3f::new(-5.0, 0.0, 0.0), X,
+                    &[(2.5, x), (3.5, X), (6.5, x), (7.5, X)]);
+        assert_hits(&torus(), Vec3f::new(2.0, 5.0, 0.0), y, &[(4.5, Y), (5.5, y)]);
+        assert_hits(&torus(), Vec3f::new(-5.0, 1.0, 0.0), X, &[]);
+        assert_hits(&torus(), Vec3f::new(0.0, 5.0, 0.0), y, &[]);
+        // Starting inside the tube, and in the hole
+        assert_hits(&torus(), Vec3f::new(-2.0, 0.0, 0.0), X, &[(0.5, X), (3.5, x), (4.5, X)]);
+        assert_hits(&torus(), Vec3f::zero(), X, &[(1.5, x), (2.5, X)]);
+        // Grazing the top of the tube at a double root
+        let top = torus().intersect(Ray { origin: Vec3f::new(-5.0, 0.5, 0.0), direction: X }, 0);
+        assert!(top.iter().all(|h| (h.normal - Y).magnitude() < 1e-2), "{:?}", top);
+
+        // Stood on its side and scaled
+        let t = Mat4f::rotate(X, 90.0).mm_multiply(&Mat4f::scale(Vec3f::new(2.0, 2.0, 2.0)));
+        let standing = placed(torus(), t);
+        assert_hits(&standing, Vec3f::new(0.0, -10.0, 0.0), Y,
+                    &[(5.0, y), (7.0, Y), (13.0, y), (15.0, Y)]);
+        assert_box(&standing, Vec3f::new(-5.0, -5.0, -1.0), Vec3f::new(5.0, 5.0, 1.0));
+    }
 }
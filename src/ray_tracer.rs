@@ -1,7 +1,40 @@
 use color::Color;
 use math::{to_radians, Vec3f, Mat4f};
-use scene::{Scene, Material, Light};
+use scene::{Scene, Material, MaterialId, DEFAULT_MATERIAL};
+use light::{Light, Illumination};
 use std::cmp::Ordering;
+use std::f32;
+use adaptive::Sample;
+use sampler::Rng;
+
+/// Offset applied to secondary ray origins to avoid self-intersection
+const EPSILON: f32 = 1e-4;
+
+/// How the light reaching the camera is computed
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum Integrator {
+    /// Phong shading with ambient light, mirror reflection and refraction
+    #[default]
+    Whitted,
+    /**
+     * Monte Carlo path tracing with indirect light, taking samples paths
+     * through each of the sampler's positions in a pixel.
+     */
+    PathTracer {
+        #[serde(default = "default_path_samples")]
+        samples: u32,
+        #[serde(default = "default_roulette_depth")]
+        roulette_depth: u32,
+    },
+}
+
+fn default_path_samples() -> u32 {
+    16
+}
+
+fn default_roulette_depth() -> u32 {
+    3
+}
 
 pub struct RayTracer<'a> {
     scene: &'a Scene,
@@ -12,22 +45,52 @@ impl<'a> RayTracer<'a> {
         RayTracer { scene: scene }
     }
 
-    pub fn trace_pixel(&self, x: u32, y: u32) -> Color {
-        let subsamples = self.scene.subsamples;
+    /**
+     * Trace one batch of samples placed in the pixel by the scene's sampler,
+     * returning their image positions and colors.  Pixel centers lie on
+     * integer coordinates, so offsets are centered on them.  Each batch is
+     * drawn with its own seed, which also seeds the samples taken of area
+     * lights from a separate stream.  Later batches scramble their seeds,
+     * since pixel streams mix the pixel into the seed and a nearby seed
+     * would repeat a neighboring pixel's samples.
+     */
+    pub fn trace_pixel(&self, x: u32, y: u32, batch: u32) -> Vec<Sample> {
+        let scene = self.scene;
+        let sampler = if batch == 0 { scene.sampler } else { scene.sampler.refinement() };
+        let seed = match batch {
+            0 => scene.seed,
+            _ => Rng::new(scene.seed.wrapping_add(batch as u64)).next_u64(),
+        };
+        let samples = sampler.samples(scene.subsamples, x, y, seed);
+        let mut rng = Rng::for_pixel(seed ^ (1 << 63), x, y);
         let (x, y) = (x as f32, y as f32);
-        let mut v = Vec3f::zero();
-        let step = 1.0 / subsamples as f32;
-        for i in 0..subsamples {
-            for j in 0..subsamples {
-                let (i, j) = (i as f32, j as f32);
-                v = v + self.trace_subpixel(x + i * step, y + j * step).vec3f();
-            }
+        samples.iter()
+            .map(|&(dx, dy)| {
+                let (sx, sy) = (x + dx - 0.5, y + dy - 0.5);
+                (sx, sy, self.trace_subpixel(sx, sy, &mut rng).vec3f())
+            })
+            .collect()
+    }
+
+    /**
+     * Color seen through image position (x, y).  The path tracer averages
+     * its samples of paths through the same position.
+     */
+    pub fn trace_subpixel(&self, x: f32, y: f32, rng: &mut Rng) -> Color {
+        let ray = self.camera_ray(x, y);
+        match self.scene.integrator {
+            Integrator::Whitted => self.trace_ray(&ray, 0, rng),
+            Integrator::PathTracer { samples, roulette_depth } => {
+                let n = samples.max(1);
+                let total = (0..n)
+                    .map(|_| self.trace_path(ray, roulette_depth, rng))
+                    .fold(Vec3f::zero(), |a, c| a + c);
+                Color::Rgb(total.scale(1.0 / n as f32))
+            },
         }
-        let avg_v = v.scale(1.0 / (subsamples * subsamples) as f32);
-        Color::Rgb(avg_v)
     }
 
-    pub fn trace_subpixel(&self, x: f32, y: f32) -> Color {
+    fn camera_ray(&self, x: f32, y: f32) -> Ray {
         let ref scene = self.scene;
         let ref camera = scene.camera;
         let d = camera.distance;
@@ -46,39 +109,276 @@ impl<'a> RayTracer<'a> {
         let d_jk = p00 + xv.scale(sj * x / (w-1.0)) - yv.scale(sk * y / (h-1.0)) - origin;
         let d_jk = d_jk.norm();
 
-        let ray = Ray { origin: origin, direction: d_jk };
+        Ray { origin: origin, direction: d_jk }
+    }
 
-        let intersections = scene.objects.intersect(ray, &scene.default_material);
-        if intersections.is_empty() {
-            scene.background
-        } else {
-            let min_intersection = intersections.iter().min().unwrap();
-            self.get_color(&ray, min_intersection)
+    /**
+     * Find the nearest intersection along the ray and shade it, unless a
+     * visible light lies in front of it.  Depth counts the number of bounces
+     * taken to reach this ray.
+     */
+    pub fn trace_ray(&self, ray: &Ray, depth: u32, rng: &mut Rng) -> Color {
+        let scene = self.scene;
+        let light = self.visible_light(*ray);
+        let t_max = light.map_or(f32::INFINITY, |(t, _)| t);
+        match scene.objects.nearest(*ray, t_max, DEFAULT_MATERIAL) {
+            Some(intx) => self.get_color(ray, &intx, depth, rng),
+            None => light.map_or(scene.background, |(_, emission)| Color::Rgb(emission)),
         }
     }
 
-    pub fn get_color(&self, ray: &Ray, intx: &Intersection) -> Color {
+    /// Distance to the nearest visible light along the ray and its color
+    fn visible_light(&self, ray: Ray) -> Option<(f32, Vec3f)> {
+        self.scene.lights.iter()
+            .filter_map(|light| light.intersect(ray))
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal))
+    }
+
+    pub fn get_color(&self, ray: &Ray, intx: &Intersection, depth: u32, rng: &mut Rng)
+                     -> Color {
         let scene = self.scene;
-        let ref material = intx.material;
+        let material = &scene.materials[intx.material];
 
         let intx_point = ray.origin + ray.direction.scale(intx.distance);
         let normal = intx.normal;
         let view = ray.direction.scale(-1.0);
 
-        let diff_spec = |light: &Light| -> Vec3f {
-            let light_dir   = (light.position - intx_point).norm();
+        let ambient = scene.ambient_light.vec3f().scale(material.k_ambient);
+        let local = material.albedo().point_mul(ambient)
+            + self.direct(intx_point, normal, view, material, rng);
+        let emitted = material.emitted();
+        let mirror = material.pbr.map_or(Vec3f::zero(), |pbr| pbr.mirror(normal, view));
+
+        let (k_reflect, k_transmit) = (material.k_reflect, material.k_transmit);
+        if (k_reflect <= 0.0 && k_transmit <= 0.0 && mirror == Vec3f::zero())
+                || depth >= scene.max_depth {
+            return Color::Rgb(local + emitted);
+        }
+
+        let (fresnel, refracted) = if k_transmit > 0.0 {
+            match dielectric(ray.direction, normal, material.ior) {
+                (fresnel, Some(direction)) => {
+                    let refracted = self.trace_secondary(intx_point, direction, depth, rng);
+                    (fresnel, refracted.scale(k_transmit * (1.0 - fresnel)))
+                },
+                (fresnel, None) => (fresnel, Vec3f::zero()),
+            }
+        } else {
+            (0.0, Vec3f::zero())
+        };
+
+        let reflect_weight = k_reflect + k_transmit * fresnel;
+        let reflected = if reflect_weight > 0.0 || mirror != Vec3f::zero() {
+            let direction = ray.direction.reflect(normal);
+            let weight = mirror + Vec3f::new(reflect_weight, reflect_weight, reflect_weight);
+            self.trace_secondary(intx_point, direction, depth, rng).point_mul(weight)
+        } else {
+            Vec3f::zero()
+        };
+
+        let local_weight = (1.0 - k_reflect - k_transmit).max(0.0);
+        Color::Rgb(local.scale(local_weight) + reflected + refracted + emitted)
+    }
+
+    /**
+     * Diffuse and specular light reaching the eye from every light by way of
+     * point, tinted by the material.  Illumination is in the units Phong
+     * shading expects, which are those of radiance over pi.
+     */
+    fn direct(&self, point: Vec3f, normal: Vec3f, view: Vec3f, material: &Material,
+              rng: &mut Rng) -> Vec3f {
+        let scene = self.scene;
+        let diff_spec = |illumination: Illumination| -> Vec3f {
+            if scene.shadows && self.in_shadow(point, &illumination) {
+                return Vec3f::zero();
+            }
+            let light_dir   = illumination.direction;
+            if let Some(ref pbr) = material.pbr {
+                let reflected = pbr.evaluate(normal, view, light_dir).scale(f32::consts::PI);
+                return illumination.radiance.point_mul(reflected);
+            }
             let reflection  = (normal.scale(normal.dot0(light_dir) * 2.0) - light_dir).norm();
             let diffuse     = material.k_diffuse * normal.dot0(light_dir);
             let specular    = material.k_specular * reflection.dot0(view).powf(material.n_shininess);
-            light.color.vec3f().scale(light.intensity * (diffuse + specular))
+            illumination.radiance.scale(diffuse + specular)
         };
+        // Area lights are averaged over several samples, each with its own
+        // shadow ray, so that shadows soften into penumbrae
+        let mut sample_light = |light: &Light| -> Vec3f {
+            let n = light.samples();
+            let total = (0..n)
+                .filter_map(|_| light.illuminate(point, rng))
+                .map(&diff_spec)
+                .fold(Vec3f::zero(), |a, l| a + l);
+            total.scale(1.0 / n as f32)
+        };
+        let light = scene.lights.iter().map(&mut sample_light).fold(Vec3f::zero(), |a, l| a + l);
+        match material.pbr {
+            Some(_) => light,
+            None => material.color.vec3f().point_mul(light),
+        }
+    }
 
-        let ambient = scene.ambient_light.vec3f().scale(material.k_ambient);
-        let light = scene.lights.iter().map(diff_spec).fold(ambient, |a, l| a + l);
-        Color::Rgb(material.color.vec3f().point_mul(light))
+    /**
+     * Trace a path from the camera, choosing one way for light to scatter at
+     * each hit.  Light is gathered along the way by sampling the lights from
+     * every diffuse surface, so lights and light-sampled emissive surfaces
+     * are only counted when hit directly if they could not have been
+     * sampled, which is from the camera or after a mirror bounce.  Scenes
+     * only prepare if every light-sampled emissive surface is a light, so
+     * none of them goes uncounted.  The background lights the scene as a
+     * distant sky.  Paths are cut short by Russian roulette after
+     * roulette_depth bounces, and end after the scene's max_depth.
+     */
+    pub fn trace_path(&self, mut ray: Ray, roulette_depth: u32, rng: &mut Rng) -> Vec3f {
+        let scene = self.scene;
+        let mut radiance = Vec3f::zero();
+        let mut throughput = Vec3f::new(1.0, 1.0, 1.0);
+        let mut specular = true;
+        for depth in 0..scene.max_depth + 1 {
+            let light = self.visible_light(ray);
+            let t_max = light.map_or(f32::INFINITY, |(t, _)| t);
+            let intx = match scene.objects.nearest(ray, t_max, DEFAULT_MATERIAL) {
+                Some(intx) => intx,
+                None => {
+                    match light {
+                        Some((_, emission)) if specular => {
+                            radiance = radiance + throughput.point_mul(emission);
+                        },
+                        Some(_) => {},
+                        None => {
+                            radiance = radiance + throughput.point_mul(scene.background.vec3f());
+                        },
+                    }
+                    break;
+                },
+            };
+            let material = &scene.materials[intx.material];
+            if specular || material.light_samples == 0 {
+                radiance = radiance + throughput.point_mul(material.emitted());
+            }
+
+            let point = ray.origin + ray.direction.scale(intx.distance);
+            let view = ray.direction.scale(-1.0);
+            // Choose between reflection, transmission and local shading in
+            // proportion to their weights
+            let (k_reflect, k_transmit) = (material.k_reflect, material.k_transmit);
+            let k_local = (1.0 - k_reflect - k_transmit).max(0.0);
+            let total = k_reflect + k_transmit + k_local;
+            if total <= 0.0 {
+                break;
+            }
+            let choice = rng.next_f32() * total;
+            let direction = if choice < k_reflect + k_transmit {
+                specular = true;
+                let reflection = ray.direction.reflect(intx.normal);
+                if choice < k_reflect {
+                    reflection
+                } else {
+                    match dielectric(ray.direction, intx.normal, material.ior) {
+                        (fresnel, Some(refraction)) if rng.next_f32() >= fresnel => refraction,
+                        _ => reflection,
+                    }
+                }
+            } else {
+                // Shade the side facing the viewer
+                let normal = if intx.normal.dot(view) < 0.0 {
+                    intx.normal.scale(-1.0)
+                } else {
+                    intx.normal
+                };
+                let direct = self.direct(point, normal, view, material, rng);
+                radiance = radiance + throughput.point_mul(direct).scale(total);
+                specular = false;
+                match material.pbr {
+                    Some(ref pbr) => match pbr.sample(normal, view, rng) {
+                        Some((direction, weight)) => {
+                            throughput = throughput.point_mul(weight);
+                            direction
+                        },
+                        None => break,
+                    },
+                    None => {
+                        let color = material.color.vec3f();
+                        throughput = throughput.point_mul(color.scale(material.k_diffuse));
+                        cosine_hemisphere(normal, rng)
+                    },
+                }
+            };
+            throughput = throughput.scale(total);
+            if throughput == Vec3f::zero() {
+                break;
+            }
+
+            if depth >= roulette_depth {
+                let survival = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+                if survival <= 0.0 || rng.next_f32() >= survival {
+                    break;
+                }
+                throughput = throughput.scale(1.0 / survival);
+            }
+            ray = Ray { origin: point + direction.scale(EPSILON), direction };
+        }
+        radiance
+    }
+
+    /// Trace a reflected or refracted ray leaving point, one bounce deeper
+    fn trace_secondary(&self, point: Vec3f, direction: Vec3f, depth: u32, rng: &mut Rng)
+                       -> Vec3f {
+        let ray = Ray { origin: point + direction.scale(EPSILON), direction };
+        self.trace_ray(&ray, depth + 1, rng).vec3f()
+    }
+
+    /**
+     * Cast a shadow ray from point toward the light and determine if any
+     * object lies between them.
+     */
+    pub fn in_shadow(&self, point: Vec3f, illumination: &Illumination) -> bool {
+        let direction = illumination.direction;
+        let ray = Ray { origin: point + direction.scale(EPSILON), direction };
+        self.scene.objects.occluded(ray, illumination.distance - EPSILON)
     }
 }
 
+/**
+ * Fresnel reflectance of a surface between air and a solid with the given
+ * refractive index, and the refracted direction unless the ray is totally
+ * internally reflected.  The normal is flipped when the ray is leaving the
+ * solid.
+ */
+fn dielectric(direction: Vec3f, normal: Vec3f, ior: f32) -> (f32, Option<Vec3f>) {
+    let entering = direction.dot(normal) < 0.0;
+    let (facing, n1, n2) = if entering {
+        (normal, 1.0, ior)
+    } else {
+        (normal.scale(-1.0), ior, 1.0)
+    };
+    match direction.refract(facing, n1 / n2) {
+        Some(refracted) => {
+            let outgoing = if n1 > n2 { refracted } else { direction };
+            (schlick(-outgoing.dot(facing), n1, n2), Some(refracted))
+        },
+        None => (1.0, None),
+    }
+}
+
+/// Random direction about the normal with density proportional to its cosine
+fn cosine_hemisphere(normal: Vec3f, rng: &mut Rng) -> Vec3f {
+    let (r, phi) = (rng.next_f32().sqrt(), 2.0 * f32::consts::PI * rng.next_f32());
+    let (u, v) = normal.orthonormal_basis();
+    let z = (1.0 - r * r).max(0.0).sqrt();
+    (u.scale(r * phi.cos()) + v.scale(r * phi.sin()) + normal.scale(z)).norm()
+}
+
+/**
+ * Schlick's approximation of the Fresnel reflectance between media with
+ * refractive indices n1 and n2, where cos is taken on the less dense side.
+ */
+fn schlick(cos: f32, n1: f32, n2: f32) -> f32 {
+    let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos).powi(5)
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Ray {
     pub origin: Vec3f,
@@ -94,19 +394,23 @@ impl Ray {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/**
+ * Ray hit.  The material is referenced by its index in the scene, so that
+ * discarded hits cost no more than a copy.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Intersection {
     pub distance: f32,
     pub normal: Vec3f,
-    pub material: Material,
+    pub material: MaterialId,
 }
 
 impl Intersection {
-    pub fn new(d: f32, n: Vec3f, m: &Material) -> Intersection {
+    pub fn new(d: f32, n: Vec3f, m: MaterialId) -> Intersection {
         Intersection {
             distance: d,
             normal: n,
-            material: m.clone(),
+            material: m,
         }
     }
 }
@@ -127,3 +431,88 @@ impl PartialOrd for Intersection {
 }
 
 impl Eq for Intersection { }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use primitive::Primitive;
+    use scene::ObjectTree;
+
+    #[test]
+    fn test_cosine_hemisphere() {
+        let normal = Vec3f::new(1.0, 2.0, -2.0).norm();
+        let mut rng = Rng::new(1);
+        let n = 100000;
+        let cosines: Vec<f32> = (0..n)
+            .map(|_| cosine_hemisphere(normal, &mut rng).dot(normal))
+            .collect();
+        assert!(cosines.iter().all(|&cos| cos >= 0.0));
+        // A density of cos / pi over the hemisphere puts a fraction c^2 of
+        // directions within cosine c of the horizon
+        for &c in &[0.25, 0.5, 0.8] {
+            let fraction = cosines.iter().filter(|&&cos| cos < c).count() as f32 / n as f32;
+            assert!((fraction - c * c).abs() < 0.01, "{} {}", c, fraction);
+        }
+    }
+
+    /**
+     * Scene inside a closed sphere of diffuse material with the given albedo,
+     * glowing with radiance one.
+     */
+    fn furnace(albedo: f32, light_samples: u32, max_depth: u32) -> Scene {
+        Scene {
+            objects: ObjectTree::Primitive(Primitive::Sphere {
+                radius: 1.0,
+                center: Vec3f::zero(),
+                transform: Mat4f::identity(),
+                inverse: Mat4f::identity(),
+            }),
+            default_material: Material {
+                k_diffuse: albedo,
+                color: Color::White,
+                emission: Color::White,
+                emission_strength: 1.0,
+                light_samples,
+                ..Material::default()
+            },
+            max_depth,
+            ..Scene::default()
+        }.prepare().expect("Unable to prepare scene")
+    }
+
+    /// Average radiance of paths leaving the center of the scene
+    fn average_path(scene: &Scene, roulette_depth: u32, n: u32) -> f32 {
+        let tracer = RayTracer::new(scene);
+        let mut rng = Rng::new(2);
+        let total: f32 = (0..n)
+            .map(|_| {
+                let direction = cosine_hemisphere(Vec3f::new(0.0, 1.0, 0.0), &mut rng);
+                let ray = Ray { origin: Vec3f::zero(), direction };
+                tracer.trace_path(ray, roulette_depth, &mut rng).x
+            })
+            .sum();
+        total / n as f32
+    }
+
+    #[test]
+    fn test_white_furnace() {
+        // Each bounce keeps half the light, so radiance converges to 1 / (1 - 1/2)
+        for &light_samples in &[0, 4] {
+            let scene = furnace(0.5, light_samples, 20);
+            assert_eq!(scene.lights.len(), light_samples.min(1) as usize);
+            let radiance = average_path(&scene, 3, 20000);
+            assert!((radiance - 2.0).abs() < 0.03, "{} {}", light_samples, radiance);
+        }
+    }
+
+    #[test]
+    fn test_russian_roulette_unbiased() {
+        // Paths of at most 7 hits gather 1 + 1/2 + ... + 1/64
+        let scene = furnace(0.5, 0, 6);
+        let expected = 2.0 - 0.5f32.powi(6);
+        let exact = average_path(&scene, 100, 2000);
+        assert!((exact - expected).abs() < 1e-4, "{}", exact);
+        let roulette = average_path(&scene, 0, 50000);
+        assert!((roulette - expected).abs() < 0.03, "{}", roulette);
+    }
+}
@@ -0,0 +1,157 @@
+//! Rectangular image regions for dividing rendering work
+
+/**
+ * Rectangle of pixels with its top-left corner at (x, y).
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// The whole image
+    pub fn image(width: u32, height: u32) -> Rect {
+        Rect { x: 0, y: 0, width, height }
+    }
+
+    /**
+     * Split into at most n bands of whole rows which cover the rectangle
+     * exactly.  Band heights differ by at most one row, and there are never
+     * more bands than rows.
+     */
+    pub fn split_rows(&self, n: u32) -> Vec<Rect> {
+        partition(self.height, n).into_iter()
+            .map(|(start, len)| Rect { y: self.y + start, height: len, .. *self })
+            .collect()
+    }
+
+    /// Split into at most n bands of whole columns, as split_rows does rows
+    pub fn split_columns(&self, n: u32) -> Vec<Rect> {
+        partition(self.width, n).into_iter()
+            .map(|(start, len)| Rect { x: self.x + start, width: len, .. *self })
+            .collect()
+    }
+
+    /**
+     * Split into tiles no larger than size on each side, in rows from the
+     * top left.  Tiles are evenly sized, so none is left as a thin sliver.
+     */
+    pub fn tiles(&self, size: u32) -> Vec<Rect> {
+        let size = size.max(1);
+        self.split_rows(self.height.div_ceil(size)).iter()
+            .flat_map(|band| band.split_columns(self.width.div_ceil(size)))
+            .collect()
+    }
+
+    /// Grow by margin pixels on every side, clipped to the bounds
+    pub fn expand(&self, margin: u32, bounds: Rect) -> Rect {
+        let (x0, y0) = (self.x.saturating_sub(margin).max(bounds.x),
+                        self.y.saturating_sub(margin).max(bounds.y));
+        let x1 = (self.x + self.width + margin).min(bounds.x + bounds.width);
+        let y1 = (self.y + self.height + margin).min(bounds.y + bounds.height);
+        Rect { x: x0, y: y0, width: x1 - x0, height: y1 - y0 }
+    }
+
+    /// Pixel coordinates in row order
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> {
+        let (x0, x1) = (self.x, self.x + self.width);
+        (self.y..self.y + self.height).flat_map(move |y| (x0..x1).map(move |x| (x, y)))
+    }
+}
+
+/**
+ * Divide len into at most n contiguous non-empty parts, giving the first
+ * len % n parts one extra element.
+ */
+fn partition(len: u32, n: u32) -> Vec<(u32, u32)> {
+    let n = n.clamp(1, len.max(1));
+    let (base, extra) = (len / n, len % n);
+    let mut start = 0;
+    (0..n)
+        .map(|i| {
+            let part = base + if i < extra { 1 } else { 0 };
+            start += part;
+            (start - part, part)
+        })
+        .filter(|&(_, part)| part > 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Assert that the regions cover the rectangle with no gaps or overlaps
+    fn assert_covers(rect: Rect, regions: &[Rect]) {
+        let mut counts = vec![0; (rect.width * rect.height) as usize];
+        for region in regions {
+            assert!(region.width > 0 && region.height > 0);
+            for (x, y) in region.pixels() {
+                assert!(x >= rect.x && x < rect.x + rect.width);
+                assert!(y >= rect.y && y < rect.y + rect.height);
+                counts[((y - rect.y) * rect.width + x - rect.x) as usize] += 1;
+            }
+        }
+        assert!(counts.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn test_split_rows() {
+        let image = Rect::image(7, 150);
+        let bands = image.split_rows(4);
+        assert_covers(image, &bands);
+        let heights: Vec<u32> = bands.iter().map(|b| b.height).collect();
+        assert_eq!(heights, vec![38, 38, 37, 37]);
+        assert_eq!(bands[3], Rect { x: 0, y: 113, width: 7, height: 37 });
+
+        for &(width, height) in &[(1, 1), (3, 5), (13, 7), (200, 151)] {
+            let image = Rect::image(width, height);
+            for n in 1..10 {
+                assert_covers(image, &image.split_rows(n));
+                assert_covers(image, &image.split_columns(n));
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_one_thread() {
+        let image = Rect::image(11, 9);
+        assert_eq!(image.split_rows(1), vec![image]);
+        assert_eq!(image.split_rows(0), vec![image]);
+    }
+
+    #[test]
+    fn test_split_more_threads_than_rows() {
+        let image = Rect::image(5, 3);
+        let bands = image.split_rows(8);
+        assert_eq!(bands.len(), 3);
+        assert!(bands.iter().all(|b| b.height == 1));
+        assert_covers(image, &bands);
+        assert!(Rect::image(5, 0).split_rows(4).is_empty());
+    }
+
+    #[test]
+    fn test_tiles() {
+        for &(width, height) in &[(1, 1), (15, 17), (16, 16), (33, 7), (301, 199)] {
+            let image = Rect::image(width, height);
+            let tiles = image.tiles(16);
+            assert_covers(image, &tiles);
+            assert!(tiles.iter().all(|t| t.width <= 16 && t.height <= 16));
+        }
+        let tiles = Rect::image(33, 7).tiles(16);
+        assert_eq!(tiles.len(), 3);
+        assert_eq!(tiles[2], Rect { x: 22, y: 0, width: 11, height: 7 });
+    }
+
+    #[test]
+    fn test_expand() {
+        let image = Rect::image(20, 10);
+        let tile = Rect { x: 1, y: 4, width: 4, height: 4 };
+        assert_eq!(tile.expand(2, image), Rect { x: 0, y: 2, width: 7, height: 8 });
+        assert_eq!(tile.expand(0, image), tile);
+        assert_eq!(image.expand(3, image), image);
+    }
+}
@@ -0,0 +1,160 @@
+//! Subpixel sampling strategies and deterministic random numbers
+
+/**
+ * Strategy for placing the samples within a pixel.  Each places
+ * subsamples x subsamples samples.
+ */
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum Sampler {
+    /// Regular grid with each sample centered in its cell
+    #[default]
+    Grid,
+    /// One uniformly random sample within each grid cell
+    Jittered,
+    /// Uniformly random samples over the whole pixel
+    Random,
+    /// Halton sequence in bases 2 and 3, randomly shifted for each pixel
+    Halton,
+}
+
+impl Sampler {
+    /**
+     * Sampler for further batches of samples in a pixel.  The grid would
+     * repeat the same samples, so it is jittered instead.
+     */
+    pub fn refinement(&self) -> Sampler {
+        match *self {
+            Sampler::Grid => Sampler::Jittered,
+            sampler => sampler,
+        }
+    }
+
+    /**
+     * Sample offsets within the pixel, each in [0, 1) on both axes.  The
+     * samples depend only on the seed and the pixel, so renders can be
+     * reproduced exactly whatever order the pixels are rendered in.
+     */
+    pub fn samples(&self, subsamples: u32, x: u32, y: u32, seed: u64) -> Vec<(f32, f32)> {
+        let n = subsamples.max(1);
+        let step = 1.0 / n as f32;
+        let mut rng = Rng::for_pixel(seed, x, y);
+        let cells = (0..n).flat_map(|j| (0..n).map(move |i| (i as f32, j as f32)));
+        match *self {
+            Sampler::Grid => {
+                cells.map(|(i, j)| ((i + 0.5) * step, (j + 0.5) * step)).collect()
+            },
+            Sampler::Jittered => {
+                cells.map(|(i, j)| {
+                    ((i + rng.next_f32()) * step, (j + rng.next_f32()) * step)
+                }).collect()
+            },
+            Sampler::Random => {
+                (0..n * n).map(|_| (rng.next_f32(), rng.next_f32())).collect()
+            },
+            Sampler::Halton => {
+                let (dx, dy) = (rng.next_f32(), rng.next_f32());
+                (1..n * n + 1).map(|i| {
+                    ((radical_inverse(2, i) + dx).fract(), (radical_inverse(3, i) + dy).fract())
+                }).collect()
+            },
+        }
+    }
+}
+
+/// Reflect the digits of i in the given base about the decimal point
+fn radical_inverse(base: u32, mut i: u32) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let (mut result, mut scale) = (0.0, inv_base);
+    while i > 0 {
+        result += (i % base) as f32 * scale;
+        i /= base;
+        scale *= inv_base;
+    }
+    result
+}
+
+/**
+ * Small, fast random number generator (SplitMix64).  It is not suitable for
+ * cryptography, only for placing samples.
+ */
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    /// Independent generator for one pixel
+    pub fn for_pixel(seed: u64, x: u32, y: u32) -> Rng {
+        let mut rng = Rng::new(seed ^ ((x as u64) << 32 | y as u64));
+        rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform random number in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLERS: [Sampler; 4] =
+        [Sampler::Grid, Sampler::Jittered, Sampler::Random, Sampler::Halton];
+
+    #[test]
+    fn test_samples_in_pixel() {
+        for sampler in SAMPLERS.iter() {
+            for n in 1..5 {
+                let samples = sampler.samples(n, 3, 7, 42);
+                assert_eq!(samples.len(), (n * n) as usize);
+                assert!(samples.iter().all(|&(x, y)| {
+                    (0.0..1.0).contains(&x) && (0.0..1.0).contains(&y)
+                }));
+            }
+        }
+    }
+
+    #[test]
+    fn test_samples_deterministic() {
+        for sampler in SAMPLERS.iter() {
+            assert_eq!(sampler.samples(3, 10, 20, 1), sampler.samples(3, 10, 20, 1));
+        }
+        let random = Sampler::Random;
+        assert!(random.samples(3, 10, 20, 1) != random.samples(3, 10, 20, 2));
+        assert!(random.samples(3, 10, 20, 1) != random.samples(3, 20, 10, 1));
+    }
+
+    #[test]
+    fn test_grid_and_jittered() {
+        assert_eq!(Sampler::Grid.samples(1, 0, 0, 0), vec![(0.5, 0.5)]);
+        assert_eq!(Sampler::Grid.samples(2, 0, 0, 0),
+                   vec![(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)]);
+
+        // One jittered sample falls in each cell of the grid
+        let samples = Sampler::Jittered.samples(4, 5, 5, 9);
+        for (k, &(x, y)) in samples.iter().enumerate() {
+            assert_eq!(((x * 4.0) as usize, (y * 4.0) as usize), (k % 4, k / 4));
+        }
+    }
+
+    #[test]
+    fn test_radical_inverse() {
+        let base2: Vec<f32> = (1..5).map(|i| radical_inverse(2, i)).collect();
+        assert_eq!(base2, vec![0.5, 0.25, 0.75, 0.125]);
+        assert!((radical_inverse(3, 4) - 4.0 / 9.0).abs() < 1e-6);
+    }
+}
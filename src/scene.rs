@@ -1,7 +1,11 @@
 //! Scene module for reading scene config from json
 
+use std::f32;
+use std::convert::TryFrom;
+use std::collections::HashMap;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use time::precise_time_s;
 use serde_json;
@@ -12,23 +16,64 @@ use color::Color;
 use math::{Vec3f, Mat4f, Transform};
 use mesh::{Mesh, Shading};
 use primitive::Primitive;
-use ray_tracer::{RayTracer, Ray, Intersection};
+use ray_tracer::{RayTracer, Ray, Intersection, Integrator};
 use bounding_box::BoundingBox;
+use bvh::{Bvh, BvhTree, BvhStats};
+use region::Rect;
+use sampler::Sampler;
+use filter::{Filter, Film};
+use adaptive::{Adaptive, Estimates, Sample};
+use microfacet::Pbr;
+use light::{self, Light};
 
 #[derive(Debug, Clone, Default, Deserialize, PartialEq)]
 pub struct Scene {
     pub image: String,
     pub width: u32,
     pub height: u32,
+    #[serde(default)]
     pub threads: u32,
     pub subsamples: u32,
+    #[serde(default)]
+    pub sampler: Sampler,
+    #[serde(default)]
+    pub filter: Filter,
+    /// Filter radius in pixels, defaulting to one suited to the filter
+    #[serde(default)]
+    pub filter_radius: Option<f32>,
+    #[serde(default)]
+    pub adaptive: Option<Adaptive>,
+    #[serde(default)]
+    pub integrator: Integrator,
+    /// Seed for random sampling, so that renders can be reproduced
+    #[serde(default)]
+    pub seed: u64,
     pub bbox_limit: u32,
     pub background: Color,
     pub camera: Camera,
     pub objects: ObjectTree,
+    #[serde(deserialize_with = "light::deserialize_lights")]
     pub lights: Vec<Light>,
     pub default_material: Material,
     pub ambient_light: Color,
+    #[serde(default = "default_shadows")]
+    pub shadows: bool,
+    #[serde(default = "default_max_depth")]
+    pub max_depth: u32,
+    /// Materials referenced by intersections, filled in by prepare
+    #[serde(skip_deserializing)]
+    pub materials: Vec<Material>,
+}
+
+/// Largest width and height of the tiles the image is rendered in
+const TILE_SIZE: u32 = 16;
+
+fn default_shadows() -> bool {
+    true
+}
+
+fn default_max_depth() -> u32 {
+    5
 }
 
 impl Scene {
@@ -41,54 +86,127 @@ impl Scene {
         serde_yaml::from_str(s).expect("Unable to decode Scene JSON")
     }
 
-    // Precompute, flatten and transform objects in the scene
-    pub fn prepare(&self) -> Scene {
+    /**
+     * Precompute, flatten and transform objects in the scene.  Fails if the
+     * scene cannot be rendered, such as when a transform is singular.
+     */
+    pub fn prepare(&self) -> Result<Scene, String> {
         let t0 = precise_time_s();
-        let new_objects = self.objects.prepare(&Mat4f::identity(), &self.camera.location);
-        let dissected_objects = new_objects.construct_bvh(self.bbox_limit);
+        let mut materials = MaterialTable::default();
+        materials.intern(&self.default_material);
+        let new_objects = self.objects.prepare(&Mat4f::identity(), &mut HashMap::new(),
+                                               &mut materials)?;
+        let mut lights = self.lights.clone();
+        new_objects.emitters(&Mat4f::identity(), DEFAULT_MATERIAL, &materials.materials,
+                             &mut lights)?;
+        let mut report = BvhReport::default();
+        let dissected_objects = new_objects.construct_bvh(self.bbox_limit, &mut report);
+        println!("Mesh BVHs over {} faces: {} leaves, depth {}, SAH cost {:.1}, \
+                  built in {:.2}s; top-level BVH over {} objects, {} unbounded: depth {}",
+                 report.faces, report.meshes.leaves, report.meshes.depth,
+                 report.meshes.sah_cost, report.build_time,
+                 report.objects, report.unbounded, report.top_depth);
         println!("Prepare time {:.2}s", precise_time_s() - t0);
-        Scene {
+        Ok(Scene {
             objects: dissected_objects,
+            lights,
+            materials: materials.materials,
             .. self.clone()
+        })
+    }
+
+    /// Number of render threads, defaulting to the number of CPUs
+    pub fn thread_count(&self) -> u32 {
+        if self.threads > 0 {
+            self.threads
+        } else {
+            thread::available_parallelism().map_or(1, |n| n.get() as u32)
         }
     }
 
+    pub fn filter_radius(&self) -> f32 {
+        self.filter_radius.unwrap_or_else(|| self.filter.default_radius())
+    }
+
+    /**
+     * Render the image in tiles.  Adaptive sampling takes two passes, first
+     * tracing one batch in every pixel and then refining each tile, so that
+     * pixels can be compared with their neighbors in other tiles.
+     */
     pub fn render(&self) {
         let t0 = precise_time_s();
-        let scene = Arc::new(self.clone());
-
-        let threads = scene.threads;
-        let handles: Vec<_> = (0..threads).map(|t| {
-            let scene = scene.clone();
-            thread::spawn(move || {
-                let ray_tracer = RayTracer::new(&scene);
-                let extra = if t == threads - 1 { scene.height % threads } else { 0 };
-                let (h, w) = (scene.height / threads + extra, scene.width);
-                let mut imgbuf = ImageBuffer::new(w, h);
-                for y in 0..h {
-                    for x in 0..w {
-                        let yy = t * h + y;
-                        imgbuf.put_pixel(x, y, ray_tracer.trace_pixel(x, yy).rgb());
-                    }
-                }
-                imgbuf
-            })
-        }).collect();
-
-        let chunk_bufs: Vec<_> = handles.into_iter().map(|h| { h.join().unwrap() }).collect();
+        let image = Rect::image(self.width, self.height);
+        let tiles = image.tiles(TILE_SIZE);
+        let film = Mutex::new(Film::new(image));
 
-        let mut imgbuf = ImageBuffer::new(scene.width, scene.height);
-        let mut y_off = 0;
-        for buf in chunk_bufs {
-            for (x, y, pixel) in buf.enumerate_pixels() {
-                imgbuf.put_pixel(x, y_off + y, *pixel);
-            }
-            y_off += buf.height();
-        }
+        let sample_count = match self.adaptive {
+            Some(ref adaptive) => {
+                let estimates = Mutex::new(Estimates::new(image));
+                let first = self.render_pass(&tiles, &film, |ray_tracer, tile| {
+                    let batches: Vec<Vec<Sample>> = tile.pixels()
+                        .map(|(x, y)| ray_tracer.trace_pixel(x, y, 0))
+                        .collect();
+                    estimates.lock().expect("Unable to lock estimates").add_tile(tile, &batches);
+                    batches.concat()
+                });
+                let estimates = estimates.into_inner().expect("Unable to take estimates");
+                first + self.render_pass(&tiles, &film, |ray_tracer, tile| {
+                    adaptive.refine_tile(tile, &estimates, |x, y, batch| {
+                        ray_tracer.trace_pixel(x, y, batch)
+                    })
+                })
+            },
+            None => {
+                self.render_pass(&tiles, &film, |ray_tracer, tile| {
+                    tile.pixels().flat_map(|(x, y)| ray_tracer.trace_pixel(x, y, 0)).collect()
+                })
+            },
+        };
+        let threads = self.thread_count();
 
-        let fout = Path::new(&scene.image);
+        let film = film.into_inner().expect("Unable to take film");
+        let imgbuf = ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            Color::Rgb(film.color(x, y)).rgb()
+        });
+        let fout = Path::new(&self.image);
         let _ = imgbuf.save(fout);
-        println!("Render time {:.2}s", precise_time_s() - t0);
+        println!("Render time {:.2}s on {} threads, {:.2} samples per pixel",
+                 precise_time_s() - t0, threads,
+                 sample_count as f64 / (self.width * self.height) as f64);
+    }
+
+    /**
+     * Trace every tile with trace(ray_tracer, tile), which the threads take
+     * from a shared counter until none are left, so that threads finishing
+     * cheap tiles keep working on the rest of the image.  Each tile's
+     * samples are splatted into a film reaching as far past the tile as the
+     * filter, and finished tiles are added straight into the film for the
+     * whole image.  Returns the number of samples traced.
+     */
+    fn render_pass<F>(&self, tiles: &[Rect], film: &Mutex<Film>, trace: F) -> usize
+    where F: Fn(&RayTracer, Rect) -> Vec<Sample> + Sync {
+        let image = Rect::image(self.width, self.height);
+        let (filter, radius) = (self.filter, self.filter_radius());
+        let next_tile = AtomicUsize::new(0);
+        let sample_count = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..self.thread_count() {
+                s.spawn(|| {
+                    let ray_tracer = RayTracer::new(self);
+                    while let Some(&tile) = tiles.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                        let samples = trace(&ray_tracer, tile);
+                        sample_count.fetch_add(samples.len(), Ordering::Relaxed);
+
+                        let mut tile_film = Film::new(tile.expand(radius.ceil() as u32, image));
+                        for (sx, sy, color) in samples {
+                            tile_film.splat(sx, sy, color, filter, radius);
+                        }
+                        film.lock().expect("Unable to lock film").merge(&tile_film);
+                    }
+                });
+            }
+        });
+        sample_count.into_inner()
     }
 
 }
@@ -105,7 +223,7 @@ pub struct Camera {
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub enum ObjectTree {
     Group(Vec<ObjectTree>),
-    Mesh(Mesh),
+    Mesh(Arc<Mesh>),
     LoadMesh {
         file: String,
         shading: Shading,
@@ -118,97 +236,478 @@ pub enum ObjectTree {
     Material {
         child: Box<ObjectTree>,
         material: Material,
+        /// Index of the material in the scene table, assigned by prepare
+        #[serde(skip_deserializing)]
+        id: MaterialId,
     },
     BoundingBox {
         child: Box<ObjectTree>,
         bbox: BoundingBox,
     },
+    /// Object placed in the scene by a transform, like Transform
+    Instance {
+        child: Box<ObjectTree>,
+        transform: Mat4f,
+        /// World to object space transform, computed by prepare
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        inverse: Mat4f,
+        /// Object to world space transform of normals, computed by prepare
+        #[serde(skip_deserializing, default = "Mat4f::identity")]
+        normal_transform: Mat4f,
+    },
+    Csg {
+        op: CsgOp,
+        left: Box<ObjectTree>,
+        right: Box<ObjectTree>,
+    },
+    /// Top-level BVH over bounded objects, built when the scene is prepared
+    #[serde(skip_deserializing)]
+    Bvh {
+        objects: Vec<ObjectTree>,
+        bvh: Bvh,
+    },
+}
+
+/// Loaded meshes keyed by file and shading, shared between instances
+type MeshCache = HashMap<(String, Shading), Arc<Mesh>>;
+
+/**
+ * Materials gathered while preparing a scene.  Identical materials share an
+ * id, since generated scenes often give each object its own copy of one of a
+ * few materials.
+ */
+#[derive(Debug, Default)]
+pub struct MaterialTable {
+    pub materials: Vec<Material>,
+}
+
+impl MaterialTable {
+    /**
+     * Id of the material, adding it unless an equal one has an id.  Scenes
+     * use few distinct materials, so a linear search is enough.
+     */
+    pub fn intern(&mut self, material: &Material) -> MaterialId {
+        match self.materials.iter().position(|m| m == material) {
+            Some(id) => id,
+            None => {
+                self.materials.push(material.clone());
+                self.materials.len() - 1
+            },
+        }
+    }
+}
+
+/**
+ * Summary of the BVHs built while preparing a scene.  Each distinct mesh is
+ * counted once, however many instances share its BVH.
+ */
+#[derive(Debug, Default)]
+pub struct BvhReport {
+    pub faces: usize,
+    /// Leaves and SAH cost summed over the mesh BVHs, and the deepest of them
+    pub meshes: BvhStats,
+    /// Seconds spent building the mesh BVHs
+    pub build_time: f64,
+    /// Objects in top-level BVHs, and those kept beside them
+    pub objects: usize,
+    pub unbounded: usize,
+    /// Depth of the deepest top-level BVH
+    pub top_depth: usize,
 }
 
 impl ObjectTree {
-    pub fn prepare(&self, t: &Mat4f, origin: &Vec3f) -> ObjectTree {
-        match *self {
+    /**
+     * Compose transforms down the tree.  Primitives are transformed directly,
+     * while meshes are left in object space and placed with an Instance.
+     * Fails if a transform is singular.
+     */
+    pub fn prepare(&self, t: &Mat4f, meshes: &mut MeshCache,
+                   materials: &mut MaterialTable) -> Result<ObjectTree, String> {
+        Ok(match *self {
             ObjectTree::Group(ref objs) => {
-                ObjectTree::Group(objs.iter().map(|o| o.prepare(t, origin)).collect())
+                ObjectTree::Group(objs.iter()
+                    .map(|o| o.prepare(t, meshes, materials))
+                    .collect::<Result<_, _>>()?)
             },
             ObjectTree::Transform { ref child, ref transform } => {
                 let new_t = t.mm_multiply(&transform.mat4f());
-                child.prepare(&new_t, origin)
+                child.prepare(&new_t, meshes, materials)?
             },
-            ObjectTree::Primitive(ref p) => ObjectTree::Primitive(p.transform(t)),
+            ObjectTree::Primitive(ref p) => ObjectTree::Primitive(p.transform(t)?),
             ObjectTree::LoadMesh { ref file, shading } => {
-                ObjectTree::Mesh(Mesh::read(Path::new(file), shading)).prepare(t, origin)
+                let mesh = meshes.entry((file.clone(), shading))
+                    .or_insert_with(|| Arc::new(Mesh::read(Path::new(file), shading).prepare()))
+                    .clone();
+                ObjectTree::Mesh(mesh).instance(t)?
             }
-            ObjectTree::Mesh(ref m) => ObjectTree::Mesh(m.transform(t, origin)),
-            ObjectTree::Material { ref child, ref material } => {
+            ObjectTree::Mesh(ref m) => ObjectTree::Mesh(Arc::new(m.prepare())).instance(t)?,
+            ObjectTree::Material { ref child, ref material, .. } => {
                 ObjectTree::Material {
-                    child: Box::new(child.prepare(t, origin)),
+                    id: materials.intern(material),
+                    child: Box::new(child.prepare(t, meshes, materials)?),
                     material: material.clone(),
                 }
             },
             ObjectTree::BoundingBox { ref child, ref bbox } => {
                 ObjectTree::BoundingBox {
-                    child: Box::new(child.prepare(t, origin)),
-                    bbox: bbox.clone(),
+                    child: Box::new(child.prepare(t, meshes, materials)?),
+                    bbox: *bbox,
+                }
+            },
+            ObjectTree::Instance { ref child, ref transform, .. } => {
+                child.prepare(&t.mm_multiply(transform), meshes, materials)?
+            },
+            ObjectTree::Csg { op, ref left, ref right } => {
+                ObjectTree::Csg {
+                    op,
+                    left: Box::new(left.prepare(t, meshes, materials)?),
+                    right: Box::new(right.prepare(t, meshes, materials)?),
                 }
             },
+            ObjectTree::Bvh { .. } => self.clone(),
+        })
+    }
+
+    /// Place an object-space subtree in the scene with transform t
+    fn instance(&self, t: &Mat4f) -> Result<ObjectTree, String> {
+        if *t == Mat4f::identity() {
+            return Ok(self.clone());
         }
+        let inverse = t.inverse()
+            .map_err(|e| format!("Unable to invert object transform: {}", e))?;
+        Ok(ObjectTree::Instance {
+            child: Box::new(self.clone()),
+            transform: *t,
+            inverse,
+            normal_transform: inverse.transpose(),
+        })
     }
 
-    pub fn construct_bvh(&self, bbox_limit: u32) -> ObjectTree {
+    /**
+     * Add a light for each instance of a mesh, sphere or disk whose material
+     * emits light and asks for light samples, so that it lights its
+     * surroundings.  Fails if any other object asks for light samples, as
+     * it could not be sampled.
+     */
+    fn emitters(&self, t: &Mat4f, material: MaterialId, materials: &[Material],
+                lights: &mut Vec<Light>) -> Result<(), String> {
+        let sampled = |id: MaterialId| {
+            let m = &materials[id];
+            Some(m).filter(|m| m.light_samples > 0 && m.emitted() != Vec3f::zero())
+        };
         match *self {
             ObjectTree::Group(ref objs) => {
-                ObjectTree::Group(objs.iter().map(|o| o.construct_bvh(bbox_limit)).collect())
+                for o in objs {
+                    o.emitters(t, material, materials, lights)?;
+                }
+            },
+            ObjectTree::Material { ref child, id, .. } => {
+                child.emitters(t, id, materials, lights)?;
+            },
+            ObjectTree::BoundingBox { ref child, .. } => {
+                child.emitters(t, material, materials, lights)?;
+            },
+            ObjectTree::Instance { ref child, ref transform, .. } => {
+                child.emitters(&t.mm_multiply(transform), material, materials, lights)?;
+            },
+            ObjectTree::Mesh(ref mesh) => {
+                if let Some(m) = sampled(material) {
+                    let triangles = mesh.triangles()
+                        .map(|tri| tri.map(|v| t.transform_point(v)))
+                        .collect();
+                    lights.push(Light::emissive(triangles, m.emitted(), m.light_samples));
+                }
+            },
+            ObjectTree::Primitive(ref p) => {
+                if let Some(m) = sampled(material) {
+                    if !p.can_sample() {
+                        return Err("Only spheres and disks can be sampled as lights".to_string());
+                    }
+                    lights.push(Light::EmissivePrimitive {
+                        primitive: p.transform(t)?,
+                        radiance: m.emitted(),
+                        samples: m.light_samples,
+                    });
+                }
+            },
+            ObjectTree::Csg { ref left, ref right, .. } => {
+                // Parts of the operands are cut away, so neither can be sampled
+                let mut operands = vec![];
+                left.emitters(t, material, materials, &mut operands)?;
+                right.emitters(t, material, materials, &mut operands)?;
+                if !operands.is_empty() {
+                    return Err("Unable to sample light from objects within a CSG".to_string());
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    pub fn construct_bvh(&self, bbox_limit: u32, report: &mut BvhReport) -> ObjectTree {
+        self.construct_shared_bvh(bbox_limit, &mut HashMap::new(), report)
+    }
+
+    /**
+     * Construct a two-level BVH.  Groups are flattened into a top-level BVH
+     * over their objects, and each distinct mesh gets its own BVH, built only
+     * once so that instances of it share the same hierarchy.
+     */
+    fn construct_shared_bvh(&self, bbox_limit: u32,
+                            dissected: &mut HashMap<*const Mesh, Arc<Mesh>>,
+                            report: &mut BvhReport) -> ObjectTree {
+        match *self {
+            ObjectTree::Group(_) | ObjectTree::Material { .. } => {
+                let mut objects = vec![];
+                self.collect_objects(None, &mut objects);
+                let objects = objects.into_iter()
+                    .map(|(o, material)| {
+                        let o = o.construct_shared_bvh(bbox_limit, dissected, report);
+                        match material {
+                            Some(&ObjectTree::Material { ref material, id, .. }) => {
+                                ObjectTree::Material {
+                                    child: Box::new(o),
+                                    material: material.clone(),
+                                    id,
+                                }
+                            },
+                            _ => o,
+                        }
+                    })
+                    .collect();
+                ObjectTree::top_level(objects, report)
             },
             ObjectTree::Transform { ref child, ref transform } => {
                 ObjectTree::Transform {
-                    child: Box::new(child.construct_bvh(bbox_limit)),
-                    transform: transform.clone(),
+                    child: Box::new(child.construct_shared_bvh(bbox_limit, dissected, report)),
+                    transform: *transform,
                 }
             },
-            ObjectTree::Primitive(ref p) => {
-                ObjectTree::BoundingBox {
-                    child: Box::new(self.clone()),
-                    bbox: p.bounding_box(),
+            ObjectTree::Mesh(ref m) => {
+                ObjectTree::Mesh(dissected.entry(&**m as *const Mesh)
+                    .or_insert_with(|| {
+                        let t0 = precise_time_s();
+                        let (mesh, stats) = m.build_bvh(bbox_limit);
+                        report.faces += mesh.faces.len();
+                        report.meshes = report.meshes.add(&stats);
+                        report.build_time += precise_time_s() - t0;
+                        Arc::new(mesh)
+                    })
+                    .clone())
+            },
+            ObjectTree::Instance { ref child, transform, inverse, normal_transform } => {
+                ObjectTree::Instance {
+                    child: Box::new(child.construct_shared_bvh(bbox_limit, dissected, report)),
+                    transform,
+                    inverse,
+                    normal_transform,
                 }
             },
-            ObjectTree::Mesh(ref m) => m.dissect(bbox_limit),
-            ObjectTree::Material { ref child, ref material } => {
-                ObjectTree::Material {
-                    child: Box::new(child.construct_bvh(bbox_limit)),
-                    material: material.clone(),
+            ObjectTree::Csg { op, ref left, ref right } => {
+                ObjectTree::Csg {
+                    op,
+                    left: Box::new(left.construct_shared_bvh(bbox_limit, dissected, report)),
+                    right: Box::new(right.construct_shared_bvh(bbox_limit, dissected, report)),
                 }
             },
-            ObjectTree::BoundingBox { .. } => {
-                self.clone()
+            ObjectTree::Primitive(_) | ObjectTree::BoundingBox { .. } |
+            ObjectTree::Bvh { .. } => self.clone(),
+            ObjectTree::LoadMesh { .. } => ObjectTree::default(),
+        }
+    }
+
+    /**
+     * Flatten groups into a list of objects, each paired with the innermost
+     * material node applied to it.
+     */
+    fn collect_objects<'a>(&'a self, material: Option<&'a ObjectTree>,
+                           objects: &mut Vec<(&'a ObjectTree, Option<&'a ObjectTree>)>) {
+        match *self {
+            ObjectTree::Group(ref objs) => {
+                for o in objs {
+                    o.collect_objects(material, objects);
+                }
             },
-            _ => ObjectTree::default()
+            ObjectTree::Material { ref child, .. } => {
+                child.collect_objects(Some(self), objects);
+            },
+            _ => objects.push((self, material)),
+        }
+    }
+
+    /**
+     * Build the top-level BVH with one object per leaf, since objects are
+     * costlier to intersect than the items the SAH cost assumes.  Unbounded
+     * objects such as planes are kept beside it and always tested.
+     */
+    fn top_level(objects: Vec<ObjectTree>, report: &mut BvhReport) -> ObjectTree {
+        let (bounded, unbounded): (Vec<_>, Vec<_>) = objects.into_iter()
+            .map(|o| (o.bounding_box(), o))
+            .partition(|(bbox, _)| bbox.is_finite());
+        let bounds: Vec<BoundingBox> = bounded.iter().map(|&(bbox, _)| bbox).collect();
+        let tree = BvhTree::build(&bounds, 1);
+        let (bvh, order) = Bvh::new(&tree);
+        report.objects += bounded.len();
+        report.unbounded += unbounded.len();
+        report.top_depth = report.top_depth.max(tree.stats().depth);
+
+        let bvh_objects = order.iter().map(|&i| bounded[i].1.clone()).collect();
+        let mut objects: Vec<ObjectTree> = unbounded.into_iter().map(|(_, o)| o).collect();
+        if !bounds.is_empty() {
+            objects.push(ObjectTree::Bvh { objects: bvh_objects, bvh });
+        }
+        match objects.len() {
+            1 => objects.pop().unwrap(),
+            _ => ObjectTree::Group(objects),
         }
     }
 
-    pub fn intersect(&self, ray: Ray, material: &Material) -> Vec<Intersection> {
+    pub fn intersect(&self, ray: Ray, material: MaterialId) -> Vec<Intersection> {
         match *self {
             ObjectTree::Group(ref objs) => {
                 objs.iter().flat_map(|o| o.intersect(ray, material).into_iter()).collect()
             },
-            ObjectTree::Transform { ref child, ref transform } => {
-                child.intersect(ray.transform(&transform.mat4f()), material)
+            ObjectTree::Instance { ref child, ref inverse, ref normal_transform, .. } => {
+                child.intersect(ray.transform(inverse), material).into_iter()
+                    .map(|intx| Intersection {
+                        normal: normal_transform.transform_normal(intx.normal),
+                        .. intx
+                    })
+                    .collect()
             },
             ObjectTree::Primitive(ref p) => p.intersect(ray, material),
             ObjectTree::Mesh(ref m) => m.intersect(ray, material),
-            ObjectTree::Material { ref child, ref material } => {
-                child.intersect(ray, material)
+            ObjectTree::Csg { op, ref left, ref right } => {
+                op.combine(ray, left.intersect(ray, material), right.intersect(ray, material))
             },
+            ObjectTree::Material { ref child, id, .. } => child.intersect(ray, id),
             ObjectTree::BoundingBox { ref child, ref bbox } => {
-                if bbox.intersect(ray) {
+                if bbox.intersect(ray).is_some() {
                     child.intersect(ray, material)
                 } else {
                     vec![]
                 }
             },
+            ObjectTree::Bvh { ref objects, ref bvh } => {
+                let mut intersections = vec![];
+                bvh.traverse(ray, f32::INFINITY, |i, t_max| {
+                    intersections.extend(objects[i].intersect(ray, material));
+                    t_max
+                });
+                intersections
+            },
             _ => vec![]
         }
     }
+
+    /**
+     * Find the nearest intersection closer than t_max.  Subtrees that the
+     * ray cannot reach before the closest hit found so far are skipped.
+     */
+    pub fn nearest(&self, ray: Ray, t_max: f32, material: MaterialId) -> Option<Intersection> {
+        match *self {
+            ObjectTree::Group(ref objs) => {
+                objs.iter().fold(None, |nearest, o| {
+                    let t_max = nearest.as_ref().map_or(t_max, |n: &Intersection| n.distance);
+                    o.nearest(ray, t_max, material).or(nearest)
+                })
+            },
+            ObjectTree::Instance { ref child, ref inverse, ref normal_transform, .. } => {
+                child.nearest(ray.transform(inverse), t_max, material)
+                    .map(|intx| Intersection {
+                        normal: normal_transform.transform_normal(intx.normal),
+                        .. intx
+                    })
+            },
+            ObjectTree::Mesh(ref m) => m.nearest(ray, t_max, material),
+            ObjectTree::Primitive(ref p) => p.nearest(ray, t_max, material),
+            ObjectTree::Csg { .. } => {
+                self.intersect(ray, material).into_iter()
+                    .filter(|intx| intx.distance < t_max)
+                    .min()
+            },
+            ObjectTree::Material { ref child, id, .. } => child.nearest(ray, t_max, id),
+            ObjectTree::BoundingBox { ref child, ref bbox } => {
+                match bbox.intersect(ray) {
+                    Some(t) if t < t_max => child.nearest(ray, t_max, material),
+                    _ => None,
+                }
+            },
+            ObjectTree::Bvh { ref objects, ref bvh } => {
+                let mut nearest = None;
+                bvh.traverse(ray, t_max, |i, t_max| {
+                    match objects[i].nearest(ray, t_max, material) {
+                        Some(intx) => {
+                            let t = intx.distance;
+                            nearest = Some(intx);
+                            t
+                        },
+                        None => t_max,
+                    }
+                });
+                nearest
+            },
+            _ => None
+        }
+    }
+
+    /**
+     * Determine if anything is hit closer than t_max, returning as soon as a
+     * hit is found.  Only constructive solids need their full intersections,
+     * and only when one of their operands is hit.
+     */
+    pub fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        match *self {
+            ObjectTree::Group(ref objs) => objs.iter().any(|o| o.occluded(ray, t_max)),
+            ObjectTree::Instance { ref child, ref inverse, .. } => {
+                child.occluded(ray.transform(inverse), t_max)
+            },
+            ObjectTree::Primitive(ref p) => p.occluded(ray, t_max),
+            ObjectTree::Mesh(ref m) => m.occluded(ray, t_max),
+            ObjectTree::Csg { ref left, ref right, .. } => {
+                // Every hit on the solid is a hit on an operand, so the full
+                // hits are only combined once an operand is hit before t_max
+                (left.occluded(ray, t_max) || right.occluded(ray, t_max)) &&
+                    self.intersect(ray, DEFAULT_MATERIAL).iter()
+                        .any(|intx| intx.distance < t_max)
+            },
+            ObjectTree::Material { ref child, .. } => child.occluded(ray, t_max),
+            ObjectTree::BoundingBox { ref child, ref bbox } => {
+                bbox.intersect(ray).is_some_and(|t| t < t_max) && child.occluded(ray, t_max)
+            },
+            ObjectTree::Bvh { ref objects, ref bvh } => {
+                bvh.any(ray, t_max, |i| objects[i].occluded(ray, t_max))
+            },
+            _ => false
+        }
+    }
+
+    pub fn bounding_box(&self) -> BoundingBox {
+        match *self {
+            ObjectTree::Group(ref objs) => {
+                objs.iter().fold(BoundingBox::empty(), |b, o| b.union(&o.bounding_box()))
+            },
+            ObjectTree::Mesh(ref m) => m.bounding_box(),
+            ObjectTree::Primitive(ref p) => p.bounding_box(),
+            ObjectTree::Transform { ref child, ref transform } => {
+                child.bounding_box().transform(&transform.mat4f())
+            },
+            ObjectTree::Material { ref child, .. } => child.bounding_box(),
+            ObjectTree::BoundingBox { ref bbox, .. } => *bbox,
+            ObjectTree::Instance { ref child, ref transform, .. } => {
+                child.bounding_box().transform(transform)
+            },
+            ObjectTree::Csg { op, ref left, ref right } => {
+                match op {
+                    CsgOp::Union => left.bounding_box().union(&right.bounding_box()),
+                    CsgOp::Intersection => left.bounding_box().intersection(&right.bounding_box()),
+                    CsgOp::Difference => left.bounding_box(),
+                }
+            },
+            ObjectTree::LoadMesh { .. } => BoundingBox::empty(),
+            ObjectTree::Bvh { ref bvh, .. } => bvh.bbox(),
+        }
+    }
 }
 
 impl Default for ObjectTree {
@@ -217,18 +716,524 @@ impl Default for ObjectTree {
     }
 }
 
-#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
-pub struct Light {
-    pub color: Color,
-    pub intensity: f32,
-    pub position: Vec3f,
+/**
+ * Constructive solid geometry operations.  Operands must be closed solids
+ * with outward facing normals, so that each hit along a ray either enters or
+ * leaves the solid.
+ */
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    Difference,
 }
 
+/// Largest cosine between a ray and a normal for a hit to graze the surface
+const GRAZING_COSINE: f32 = 1e-6;
+
+impl CsgOp {
+    fn contains(&self, in_left: bool, in_right: bool) -> bool {
+        match *self {
+            CsgOp::Union => in_left || in_right,
+            CsgOp::Intersection => in_left && in_right,
+            CsgOp::Difference => in_left && !in_right,
+        }
+    }
+
+    /**
+     * Combine the entry and exit hits of both operands into the hits on the
+     * surface of the resulting solid.  Walking the hits in order, a surface
+     * is crossed wherever being inside the result changes.  A ray starts
+     * inside an operand if its first hit leaves it.  Hits grazing an operand
+     * neither enter nor leave it and are dropped, and hits at the same
+     * distance are crossed together so that coincident surfaces leave no
+     * sliver between them.
+     */
+    pub fn combine(&self, ray: Ray, mut left: Vec<Intersection>,
+                   mut right: Vec<Intersection>) -> Vec<Intersection> {
+        let grazing = |h: &Intersection| {
+            h.normal.dot(ray.direction).abs() <= GRAZING_COSINE * ray.direction.magnitude()
+        };
+        left.retain(|h| !grazing(h));
+        right.retain(|h| !grazing(h));
+        left.sort();
+        right.sort();
+        let starts_inside = |hits: &Vec<Intersection>| {
+            hits.first().is_some_and(|h| h.normal.dot(ray.direction) > 0.0)
+        };
+        let (mut in_left, mut in_right) = (starts_inside(&left), starts_inside(&right));
+        let mut inside = self.contains(in_left, in_right);
+
+        let mut events: Vec<(Intersection, bool)> = left.into_iter().map(|h| (h, true))
+            .chain(right.into_iter().map(|h| (h, false)))
+            .collect();
+        events.sort_by_key(|a| a.0);
+
+        let mut hits = vec![];
+        for group in events.chunk_by(|a, b| a.0.distance == b.0.distance) {
+            let was_inside = inside;
+            let mut crossing = None;
+            for &(hit, is_left) in group {
+                if is_left {
+                    in_left = !in_left;
+                } else {
+                    in_right = !in_right;
+                }
+                let now_inside = self.contains(in_left, in_right);
+                if now_inside != inside {
+                    inside = now_inside;
+                    crossing = Some((hit, is_left));
+                }
+            }
+            match crossing {
+                Some((hit, is_left)) if inside != was_inside => {
+                    if *self == CsgOp::Difference && !is_left {
+                        hits.push(Intersection { normal: hit.normal.scale(-1.0), .. hit });
+                    } else {
+                        hits.push(hit);
+                    }
+                },
+                _ => {},
+            }
+        }
+        hits
+    }
+}
+
+/// Index into the scene's material table
+pub type MaterialId = usize;
+
+/// The scene's default material, which is always first in the table
+pub const DEFAULT_MATERIAL: MaterialId = 0;
+
+/**
+ * Surface material.  Surfaces are shaded with the Phong model, unless the
+ * material has physically based parameters, in which case those replace
+ * the diffuse and specular terms and the color.
+ */
 #[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(try_from = "MaterialFormat")]
 pub struct Material {
     pub k_diffuse: f32,
     pub k_specular: f32,
     pub k_ambient: f32,
     pub n_shininess: f32,
     pub color: Color,
+    pub pbr: Option<Pbr>,
+    pub k_reflect: f32,
+    pub k_transmit: f32,
+    pub ior: f32,
+    /// Color of light the surface gives off, on both sides
+    pub emission: Color,
+    pub emission_strength: f32,
+    /**
+     * Number of shadow samples to take of emissive meshes, spheres and disks,
+     * which are only sampled as lights if this is positive.  Other objects
+     * cannot have emissive materials with light samples.
+     */
+    pub light_samples: u32,
+}
+
+/**
+ * Material as written in scenes.  The Phong terms are required unless the
+ * material is physically based, so that a mistyped Phong material is an
+ * error rather than a black surface.
+ */
+#[derive(Deserialize)]
+struct MaterialFormat {
+    k_diffuse: Option<f32>,
+    k_specular: Option<f32>,
+    k_ambient: Option<f32>,
+    n_shininess: Option<f32>,
+    color: Option<Color>,
+    #[serde(default)]
+    pbr: Option<Pbr>,
+    #[serde(default)]
+    k_reflect: f32,
+    #[serde(default)]
+    k_transmit: f32,
+    #[serde(default = "default_ior")]
+    ior: f32,
+    #[serde(default)]
+    emission: Color,
+    #[serde(default = "default_emission_strength")]
+    emission_strength: f32,
+    #[serde(default)]
+    light_samples: u32,
+}
+
+impl TryFrom<MaterialFormat> for Material {
+    type Error = String;
+
+    fn try_from(m: MaterialFormat) -> Result<Material, String> {
+        fn phong<T: Default>(value: Option<T>, name: &str, pbr: bool) -> Result<T, String> {
+            match value {
+                Some(value) => Ok(value),
+                None if pbr => Ok(T::default()),
+                None => Err(format!("missing field `{}` of a material without pbr", name)),
+            }
+        }
+        let pbr = m.pbr.is_some();
+        Ok(Material {
+            k_diffuse: phong(m.k_diffuse, "k_diffuse", pbr)?,
+            k_specular: phong(m.k_specular, "k_specular", pbr)?,
+            k_ambient: phong(m.k_ambient, "k_ambient", pbr)?,
+            n_shininess: phong(m.n_shininess, "n_shininess", pbr)?,
+            color: phong(m.color, "color", pbr)?,
+            pbr: m.pbr,
+            k_reflect: m.k_reflect,
+            k_transmit: m.k_transmit,
+            ior: m.ior,
+            emission: m.emission,
+            emission_strength: m.emission_strength,
+            light_samples: m.light_samples,
+        })
+    }
+}
+
+fn default_ior() -> f32 {
+    1.0
+}
+
+fn default_emission_strength() -> f32 {
+    1.0
+}
+
+impl Material {
+    /// Color of diffuse light from the surface
+    pub fn albedo(&self) -> Vec3f {
+        self.pbr.map_or(self.color, |pbr| pbr.base_color).vec3f()
+    }
+
+    /// Light given off by the surface
+    pub fn emitted(&self) -> Vec3f {
+        self.emission.vec3f().scale(self.emission_strength)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use sampler::Rng;
+
+    /// Write a mesh with the given vertices to a temporary SMF file
+    fn smf_file(name: &str, vertices: &[Vec3f]) -> String {
+        let path = env::temp_dir().join(format!("raylang-{}-{}.smf", name, std::process::id()));
+        let mut smf: String = vertices.iter()
+            .map(|v| format!("v {} {} {}\n", v.x, v.y, v.z))
+            .collect();
+        smf.push_str("f 1 2 3\nf 1 3 4\nf 1 4 2\nf 2 4 3\n");
+        fs::write(&path, smf).expect("Unable to write mesh");
+        path.to_string_lossy().into_owned()
+    }
+
+    fn load(file: &str) -> ObjectTree {
+        ObjectTree::LoadMesh { file: file.to_string(), shading: Shading::Flat }
+    }
+
+    fn prepare(objects: &ObjectTree) -> ObjectTree {
+        objects.prepare(&Mat4f::identity(), &mut HashMap::new(), &mut MaterialTable::default())
+            .expect("Unable to prepare objects")
+            .construct_bvh(4, &mut BvhReport::default())
+    }
+
+    /// Meshes in the tree with the transforms placing them
+    fn meshes(objects: &ObjectTree, found: &mut Vec<(Arc<Mesh>, Mat4f)>) {
+        match *objects {
+            ObjectTree::Group(ref objs) | ObjectTree::Bvh { objects: ref objs, .. } => {
+                for o in objs {
+                    meshes(o, found);
+                }
+            },
+            ObjectTree::Instance { ref child, transform, .. } => {
+                if let ObjectTree::Mesh(ref m) = **child {
+                    found.push((m.clone(), transform));
+                }
+            },
+            ObjectTree::Mesh(ref m) => found.push((m.clone(), Mat4f::identity())),
+            _ => {},
+        }
+    }
+
+    const X: Vec3f = Vec3f { x: 1.0, y: 0.0, z: 0.0 };
+
+    fn ray() -> Ray {
+        Ray { origin: Vec3f::zero(), direction: X }
+    }
+
+    /// Hits entering a solid along the x axis at t0 and leaving at t1
+    fn interval(t0: f32, t1: f32) -> Vec<Intersection> {
+        vec![Intersection::new(t1, X, 0), Intersection::new(t0, X.scale(-1.0), 0)]
+    }
+
+    /// Distances of hits, negative where they leave the solid
+    fn crossings(hits: Vec<Intersection>) -> Vec<f32> {
+        hits.iter().map(|h| if h.normal.dot(X) > 0.0 { -h.distance } else { h.distance }).collect()
+    }
+
+    fn combine(op: CsgOp, left: Vec<Intersection>, right: Vec<Intersection>) -> Vec<f32> {
+        crossings(op.combine(ray(), left, right))
+    }
+
+    #[test]
+    fn test_csg_intervals() {
+        // Overlapping, disjoint and nested operands
+        assert_eq!(combine(CsgOp::Union, interval(1.0, 3.0), interval(2.0, 4.0)), vec![1.0, -4.0]);
+        assert_eq!(combine(CsgOp::Union, interval(1.0, 2.0), interval(3.0, 4.0)),
+                   vec![1.0, -2.0, 3.0, -4.0]);
+        assert_eq!(combine(CsgOp::Union, interval(1.0, 4.0), interval(2.0, 3.0)), vec![1.0, -4.0]);
+        assert_eq!(combine(CsgOp::Intersection, interval(1.0, 3.0), interval(2.0, 4.0)),
+                   vec![2.0, -3.0]);
+        assert_eq!(combine(CsgOp::Intersection, interval(1.0, 2.0), interval(3.0, 4.0)),
+                   Vec::<f32>::new());
+        assert_eq!(combine(CsgOp::Intersection, interval(1.0, 4.0), interval(2.0, 3.0)),
+                   vec![2.0, -3.0]);
+        // Surfaces cut by the right operand face out of the left
+        assert_eq!(combine(CsgOp::Difference, interval(1.0, 3.0), interval(2.0, 4.0)),
+                   vec![1.0, -2.0]);
+        assert_eq!(combine(CsgOp::Difference, interval(1.0, 2.0), interval(3.0, 4.0)),
+                   vec![1.0, -2.0]);
+        assert_eq!(combine(CsgOp::Difference, interval(1.0, 4.0), interval(2.0, 3.0)),
+                   vec![1.0, -2.0, 3.0, -4.0]);
+        assert_eq!(combine(CsgOp::Difference, interval(2.0, 3.0), interval(1.0, 4.0)),
+                   Vec::<f32>::new());
+    }
+
+    #[test]
+    fn test_csg_starting_inside() {
+        // The ray starts inside the left operand, leaving it at 2
+        let inside = || vec![Intersection::new(2.0, X, 0)];
+        assert_eq!(combine(CsgOp::Union, inside(), interval(1.0, 3.0)), vec![-3.0]);
+        assert_eq!(combine(CsgOp::Intersection, inside(), interval(1.0, 3.0)), vec![1.0, -2.0]);
+        assert_eq!(combine(CsgOp::Difference, inside(), interval(1.0, 3.0)), vec![-1.0]);
+        assert_eq!(combine(CsgOp::Difference, interval(1.0, 3.0), inside()), vec![2.0, -3.0]);
+    }
+
+    #[test]
+    fn test_csg_grazing_and_coincident() {
+        // A hit tangent to an operand neither enters nor leaves it
+        let tangent = || vec![Intersection::new(1.0, Vec3f::new(0.0, 1.0, 0.0), 0)];
+        assert_eq!(combine(CsgOp::Union, tangent(), interval(2.0, 3.0)), vec![2.0, -3.0]);
+        assert_eq!(combine(CsgOp::Intersection, tangent(), interval(2.0, 3.0)), Vec::<f32>::new());
+        // Coincident surfaces are crossed once, leaving no sliver
+        assert_eq!(combine(CsgOp::Union, interval(1.0, 3.0), interval(1.0, 3.0)), vec![1.0, -3.0]);
+        assert_eq!(combine(CsgOp::Intersection, interval(1.0, 3.0), interval(1.0, 3.0)),
+                   vec![1.0, -3.0]);
+        assert_eq!(combine(CsgOp::Difference, interval(1.0, 3.0), interval(1.0, 3.0)),
+                   Vec::<f32>::new());
+        assert_eq!(combine(CsgOp::Union, interval(1.0, 2.0), interval(2.0, 3.0)), vec![1.0, -3.0]);
+    }
+
+    #[test]
+    fn test_csg_solids() {
+        let sphere = |x: f32| ObjectTree::Primitive(Primitive::Sphere {
+            radius: 1.0,
+            center: Vec3f::new(x, 0.0, 0.0),
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
+        });
+        let csg = |op| ObjectTree::Csg {
+            op,
+            left: Box::new(sphere(0.0)),
+            right: Box::new(sphere(1.0)),
+        };
+        let from = |x: f32| Ray { origin: Vec3f::new(x, 0.0, 0.0), direction: X };
+        // The bite out of the left sphere faces back into the hole
+        let bitten = csg(CsgOp::Difference).intersect(from(-3.0), DEFAULT_MATERIAL);
+        assert_eq!(bitten.len(), 2);
+        assert!((bitten[1].distance - 3.0).abs() < 1e-5);
+        assert!((bitten[1].normal - X).magnitude() < 1e-5);
+        // A ray starting inside both spheres leaves their intersection first
+        let lens = csg(CsgOp::Intersection).nearest(from(0.5), f32::INFINITY, DEFAULT_MATERIAL)
+            .expect("Unable to hit lens");
+        assert!((lens.distance - 0.5).abs() < 1e-5);
+        assert!(csg(CsgOp::Intersection).occluded(from(0.5), 1.0));
+        // Hitting an operand is not enough to be occluded by the solid
+        assert!(!csg(CsgOp::Intersection).occluded(from(-3.0), 2.5));
+        assert!(csg(CsgOp::Intersection).occluded(from(-3.0), 3.5));
+        assert!(!csg(CsgOp::Union).occluded(from(-3.0), 1.5));
+        // Disjoint operands have an empty intersection
+        let apart = ObjectTree::Csg {
+            op: CsgOp::Intersection,
+            left: Box::new(sphere(0.0)),
+            right: Box::new(sphere(5.0)),
+        };
+        assert_eq!(apart.bounding_box(), BoundingBox::empty());
+        assert!(apart.intersect(from(-3.0), DEFAULT_MATERIAL).is_empty());
+    }
+
+    #[test]
+    fn test_sampled_emitters() {
+        let glowing = |child: ObjectTree| ObjectTree::Material {
+            child: Box::new(child),
+            material: Material {
+                emission: Color::White,
+                emission_strength: 1.0,
+                light_samples: 4,
+                ..Material::default()
+            },
+            id: DEFAULT_MATERIAL,
+        };
+        let unit = Mat4f::identity();
+        let sphere = ObjectTree::Primitive(Primitive::Sphere {
+            radius: 1.0,
+            center: Vec3f::zero(),
+            transform: unit,
+            inverse: unit,
+        });
+        let cube = ObjectTree::Primitive(Primitive::Box {
+            min: Vec3f::zero(),
+            max: Vec3f::new(1.0, 1.0, 1.0),
+            transform: unit,
+            inverse: unit,
+        });
+        let emitters = |objects: ObjectTree| {
+            let mut materials = MaterialTable::default();
+            materials.intern(&Material::default());
+            let mut lights = vec![];
+            objects.prepare(&unit, &mut HashMap::new(), &mut materials)
+                .and_then(|o| {
+                    o.emitters(&unit, DEFAULT_MATERIAL, &materials.materials, &mut lights)
+                })
+                .map(|_| lights.len())
+        };
+        assert_eq!(emitters(glowing(sphere.clone())), Ok(1));
+        assert!(emitters(glowing(cube.clone())).is_err());
+        assert!(emitters(ObjectTree::Csg {
+            op: CsgOp::Difference,
+            left: Box::new(glowing(sphere.clone())),
+            right: Box::new(cube.clone()),
+        }).is_err());
+        // Objects without emissive materials are not lights
+        assert_eq!(emitters(cube), Ok(0));
+    }
+
+    #[test]
+    fn test_singular_transforms_fail() {
+        let sphere = Primitive::Sphere {
+            radius: 1.0,
+            center: Vec3f::zero(),
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
+        };
+        let flattened = ObjectTree::Transform {
+            child: Box::new(ObjectTree::Primitive(sphere)),
+            transform: Transform::Scale(Vec3f::new(1.0, 0.0, 1.0)),
+        };
+        let prepared = flattened.prepare(&Mat4f::identity(), &mut HashMap::new(),
+                                         &mut MaterialTable::default());
+        assert!(prepared.is_err());
+    }
+
+    #[test]
+    fn test_instances_share_meshes() {
+        let tetrahedron = [Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(1.0, 0.0, 0.0),
+                           Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(0.0, 0.0, 1.0)];
+        let t = Mat4f::translate(Vec3f::new(1.0, 2.0, -3.0))
+            .mm_multiply(&Mat4f::rotate(Vec3f::new(1.0, 1.0, 0.0).norm(), 40.0))
+            .mm_multiply(&Mat4f::scale(Vec3f::new(1.5, 1.5, 1.5)));
+        // Vertex normals are averaged in object space, so only similarity
+        // transforms give the same normals as moving the vertices
+        let moved: Vec<Vec3f> = tetrahedron.iter().map(|&v| t.transform_point(v)).collect();
+        let file = smf_file("instanced", &tetrahedron);
+        let moved_file = smf_file("moved", &moved);
+
+        let instanced = prepare(&ObjectTree::Group(vec![
+            ObjectTree::Instance {
+                child: Box::new(load(&file)),
+                transform: t,
+                inverse: Mat4f::identity(),
+                normal_transform: Mat4f::identity(),
+            },
+            ObjectTree::Transform {
+                child: Box::new(load(&file)),
+                transform: Transform::Translate(Vec3f::new(10.0, 0.0, 0.0)),
+            },
+        ]));
+        let mut found = vec![];
+        meshes(&instanced, &mut found);
+        assert_eq!(found.len(), 2);
+        assert!(Arc::ptr_eq(&found[0].0, &found[1].0));
+
+        let original = prepare(&load(&moved_file));
+        let center = moved.iter().fold(Vec3f::zero(), |c, &v| c + v.scale(0.25));
+        let mut rng = Rng::new(1);
+        for _ in 0..100 {
+            let origin = Vec3f::new(rng.next_f32(), rng.next_f32(), rng.next_f32())
+                .scale(8.0) - Vec3f::new(4.0, 4.0, 4.0) + center;
+            let offset = Vec3f::new(rng.next_f32(), rng.next_f32(), rng.next_f32()).scale(0.5);
+            let ray = Ray { origin, direction: (center + offset - origin).norm() };
+            let expected = original.nearest(ray, f32::INFINITY, DEFAULT_MATERIAL);
+            let actual = instanced.nearest(ray, f32::INFINITY, DEFAULT_MATERIAL);
+            assert_eq!(expected.is_some(), actual.is_some());
+            if let (Some(e), Some(a)) = (expected, actual) {
+                assert!((e.distance - a.distance).abs() < 1e-4, "{:?} {:?}", e, a);
+                assert!((e.normal - a.normal).magnitude() < 1e-4, "{:?} {:?}", e, a);
+            }
+        }
+        fs::remove_file(file).expect("Unable to remove mesh");
+        fs::remove_file(moved_file).expect("Unable to remove mesh");
+    }
+
+    #[test]
+    fn test_meshes_without_faces_are_missed() {
+        let path = env::temp_dir().join(format!("raylang-empty-{}.smf", std::process::id()));
+        fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\n").expect("Unable to write mesh");
+        let file = path.to_string_lossy().into_owned();
+        let ray = Ray {
+            origin: Vec3f::new(0.25, 0.25, -1.0),
+            direction: Vec3f::new(0.0, 0.0, 1.0),
+        };
+        let sphere = ObjectTree::Primitive(Primitive::Sphere {
+            radius: 1.0,
+            center: Vec3f::new(0.0, 0.0, 5.0),
+            transform: Mat4f::identity(),
+            inverse: Mat4f::identity(),
+        });
+        for objects in &[load(&file), ObjectTree::Group(vec![load(&file), sphere])] {
+            let prepared = prepare(objects);
+            let hits = prepared.intersect(ray, DEFAULT_MATERIAL);
+            assert!(hits.iter().all(|i| i.distance > 3.0), "{:?}", hits);
+            assert!(!prepared.occluded(ray, 3.0));
+            assert!(prepared.nearest(ray, 3.0, DEFAULT_MATERIAL).is_none());
+        }
+        fs::remove_file(file).expect("Unable to remove mesh");
+    }
+
+    #[test]
+    fn test_identical_materials_share_ids() {
+        let red = Material { color: Color::Rgb(Vec3f::new(1.0, 0.0, 0.0)), .. Material::default() };
+        let painted = |material: &Material| ObjectTree::Material {
+            material: material.clone(),
+            child: Box::new(ObjectTree::default()),
+            id: DEFAULT_MATERIAL,
+        };
+        let scene = Scene {
+            objects: ObjectTree::Group(vec![painted(&red), painted(&Material::default()),
+                                            painted(&red)]),
+            .. Scene::default()
+        }.prepare().expect("Unable to prepare scene");
+        assert_eq!(scene.materials, vec![Material::default(), red]);
+    }
+
+    #[test]
+    fn test_material_requires_phong_terms() {
+        let phong = r#"{"k_diffuse": 0.8, "k_specular": 0.2, "k_ambient": 0.1,
+                        "n_shininess": 10.0, "color": "Red"}"#;
+        let material: Material = serde_json::from_str(phong).expect("Unable to decode Material");
+        assert_eq!(material.k_diffuse, 0.8);
+        assert_eq!(material.color, Color::Red);
+
+        let mistyped = r#"{"k_difuse": 0.8, "k_specular": 0.2, "k_ambient": 0.1,
+                           "n_shininess": 10.0, "color": "Red"}"#;
+        let error = serde_json::from_str::<Material>(mistyped).unwrap_err();
+        assert!(error.to_string().contains("k_diffuse"), "{}", error);
+
+        let pbr = r#"{"pbr": {"base_color": "Red", "metallic": 1.0}}"#;
+        let material: Material = serde_json::from_str(pbr).expect("Unable to decode Material");
+        assert_eq!(material.k_diffuse, 0.0);
+        assert_eq!(material.pbr.map(|pbr| pbr.roughness), Some(0.5));
+    }
 }